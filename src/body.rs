@@ -0,0 +1,60 @@
+use crate::types::RequestError;
+use bytes::{Buf, Bytes};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::mpsc,
+};
+
+/// A streamed response body, see [`Client::request_streaming`](crate::Client::request_streaming).
+/// Stream errors, including the connection closing before the body has ended, are
+/// returned as [`io::Error`]s.
+#[derive(Debug)]
+pub struct BodyReader {
+    incoming: mpsc::UnboundedReceiver<Result<Bytes, RequestError>>,
+    chunk: Bytes,
+}
+
+impl BodyReader {
+    pub(crate) fn new(incoming: mpsc::UnboundedReceiver<Result<Bytes, RequestError>>) -> Self {
+        Self {
+            incoming,
+            chunk: Bytes::new(),
+        }
+    }
+
+    /// The next chunk of the body as it was received, or None at the end of the body.
+    pub async fn chunk(&mut self) -> Option<Result<Bytes, RequestError>> {
+        if self.chunk.has_remaining() {
+            return Some(Ok(std::mem::take(&mut self.chunk)));
+        }
+        self.incoming.recv().await
+    }
+}
+
+impl AsyncRead for BodyReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while !self.chunk.has_remaining() {
+            match self.incoming.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.chunk = chunk,
+                Poll::Ready(Some(Err(err))) => {
+                    return Poll::Ready(Err(io::Error::other(err)));
+                }
+                // end of the body
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = self.chunk.len().min(buf.remaining());
+        buf.put_slice(&self.chunk.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
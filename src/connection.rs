@@ -1,24 +1,200 @@
 use crate::{
-    flags::*, frame::*, request::Request, response::Response, stream_coordinator::*, types::*,
+    body::BodyReader,
+    flags::*,
+    frame::*,
+    observer::{dump_frame, Direction, FrameDump, FrameInfo, FrameObserver},
+    proxy,
+    request::{ascii_host, check_scheme, Method, Progress, Request},
+    response::{Response, Timing, TlsSession},
+    stats::{Stats, StatsCounters},
+    stream::{discard_header_block, Stream},
+    stream_coordinator::*,
+    tcp,
+    tls::{self, Connector, Pin, TlsBackend, TlsStream},
+    tunnel::Tunnel,
+    types::*,
+    window::ReceiveWindow,
 };
 use anyhow::anyhow;
 use bytes::{Buf, Bytes, BytesMut};
 use derivative::Derivative;
 use enum_map::{enum_map, EnumMap};
 use log::{debug, error, trace, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt},
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
     sync::{mpsc, oneshot},
+    time::{sleep_until, Duration, Instant},
 };
-use tokio_rustls::TlsConnector;
 use url::Url;
 
+/// The initial values of the settings, before any SETTINGS frames.
+fn initial_settings() -> EnumMap<SettingsParameter, u32> {
+    enum_map! {
+        SettingsParameter::HeaderTableSize => 4096,
+        SettingsParameter::EnablePush => 1,
+        SettingsParameter::MaxConcurrentStreams => u32::MAX,
+        SettingsParameter::InitialWindowSize => 65_535,
+        SettingsParameter::MaxFrameSize => 16_384,
+        SettingsParameter::MaxHeaderListSize => u32::MAX,
+        SettingsParameter::EnableConnectProtocol => 0,
+    }
+}
+
+/// Shortens `duration` by up to a tenth at random, so that connections opened together
+/// don't all expire together.
+fn jittered(duration: Duration) -> Duration {
+    let random = random_u64();
+    duration.saturating_sub(duration / 10_000 * (random % 1000) as u32)
+}
+
+/// Resolves a host name and port into the addresses to connect to, in order of preference.
+pub type Resolver = Arc<dyn Fn(&str, u16) -> Vec<SocketAddr> + Send + Sync>;
+
+/// The addresses of the host of `url`, with the configured [`Resolver`] if there is one,
+/// otherwise with the system's, off the runtime's worker threads.
+pub async fn resolve(url: &Url, options: &ConnectionOptions) -> anyhow::Result<Vec<SocketAddr>> {
+    let host = ascii_host(url)?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("connect port"))?;
+    if let Some(ref resolver) = options.resolver {
+        Ok(resolver(&host, port))
+    } else {
+        // IPv6 addresses keep their brackets, so that the port can follow
+        Ok(tokio::net::lookup_host(format!("{}:{}", host, port))
+            .await?
+            .collect())
+    }
+}
+
+/// Configuration for new connections, set through [`ClientBuilder`](crate::ClientBuilder).
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ConnectionOptions {
+    /// sent to the peer in our initial SETTINGS, and enforced on what it sends
+    pub settings: EnumMap<SettingsParameter, u32>,
+    /// limit for the header block fragments buffered until END_HEADERS
+    pub max_header_block_size: usize,
+    /// the connection-level receive window grows up to this with BDP estimation
+    pub max_receive_window: u32,
+    /// limit for buffered response bodies, streamed bodies aren't limited
+    pub max_body_size: usize,
+    /// reset streams that get no response HEADERS within this long
+    pub headers_timeout: Option<Duration>,
+    /// send the body of an `expect: 100-continue` request anyway if no 100 Continue arrives
+    /// within this long
+    pub expect_continue_timeout: Duration,
+    /// error on connection-specific request headers instead of dropping them
+    pub reject_connection_headers: bool,
+    /// lowercase response header names instead of treating uppercase as malformed
+    pub lenient_header_names: bool,
+    /// the most our HPACK encoder's dynamic table may take, on top of the peer's limit
+    pub encoder_table_size: u32,
+    /// lowercase names of the headers never to index in HPACK
+    pub sensitive_headers: Vec<String>,
+    /// include the port in `:authority` even when it's the scheme's default
+    pub default_port_in_authority: bool,
+    /// how many requests can be queued for the connection task before senders have to wait
+    pub request_channel_capacity: usize,
+    /// send the connection preface as TLS 0-RTT early data when resuming a session
+    pub early_data: bool,
+    /// send a PING this often to keep the RTT estimate up to date
+    pub ping_interval: Option<Duration>,
+    /// close the connection once it has had no streams for this long
+    pub idle_timeout: Option<Duration>,
+    /// stop opening streams on the connection after about this long, and close it once
+    /// the streams in flight have completed
+    pub max_lifetime: Option<Duration>,
+    /// the local address to bind outgoing sockets to
+    pub local_addr: Option<SocketAddr>,
+    /// an `http` proxy to tunnel connections through with CONNECT, or a `socks5` proxy
+    pub proxy: Option<Url>,
+    /// also offer `http/1.1` with ALPN, and send requests with HTTP/1.1 when it's chosen
+    pub http1_fallback: bool,
+    /// skip verifying the server's certificate, for testing only
+    pub danger_accept_invalid_certs: bool,
+    /// a certificate of the server's chain must match one of these, unless empty
+    pub pins: Vec<Pin>,
+    /// ASCII host names and the server name to send with SNI instead, which the certificate
+    /// is verified for
+    pub server_names: HashMap<String, String>,
+    /// the TLS implementation to connect with
+    pub tls_backend: TlsBackend,
+    /// trust the system's root certificates instead of the bundled ones with rustls
+    pub native_roots: bool,
+    /// used instead of the system resolver
+    #[derivative(Debug = "ignore")]
+    pub resolver: Option<Resolver>,
+    /// called with every frame sent and received
+    #[derivative(Debug = "ignore")]
+    pub frame_observer: Option<FrameObserver>,
+    /// written to with every frame sent and received, like `nghttp -v`
+    #[derivative(Debug = "ignore")]
+    pub frame_dump: Option<FrameDump>,
+}
+
+impl Default for ConnectionOptions {
+    #[must_use]
+    fn default() -> Self {
+        let mut settings = initial_settings();
+        // what each stream may have in flight, the connection window grows on its own
+        settings[SettingsParameter::InitialWindowSize] = 4 << 20;
+        settings[SettingsParameter::MaxHeaderListSize] = 16 << 20;
+        Self {
+            settings,
+            max_header_block_size: 256 << 10,
+            max_body_size: 100 << 20,
+            max_receive_window: 16 << 20,
+            headers_timeout: None,
+            expect_continue_timeout: Duration::from_secs(1),
+            reject_connection_headers: false,
+            lenient_header_names: false,
+            encoder_table_size: 4096,
+            sensitive_headers: [
+                "authorization",
+                "proxy-authorization",
+                "cookie",
+                "set-cookie",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+            default_port_in_authority: false,
+            request_channel_capacity: 256,
+            early_data: true,
+            ping_interval: None,
+            idle_timeout: None,
+            max_lifetime: None,
+            local_addr: None,
+            proxy: None,
+            http1_fallback: false,
+            danger_accept_invalid_certs: false,
+            pins: Vec::new(),
+            server_names: HashMap::new(),
+            tls_backend: TlsBackend::default(),
+            native_roots: false,
+            resolver: None,
+            frame_observer: None,
+            frame_dump: None,
+        }
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct ConnectionState {
+    /// our settings are `options.settings`
+    pub options: ConnectionOptions,
     pub their_settings: EnumMap<SettingsParameter, u32>,
     pub window_remaining: usize,
+    pub receive_window: ReceiveWindow,
     #[derivative(Debug = "ignore")]
     pub header_encoder: hpack::Encoder<'static>,
     #[derivative(Debug = "ignore")]
@@ -27,49 +203,394 @@ pub struct ConnectionState {
     pub write_buf: BytesMut,
     pub header: Option<FrameHeader>,
     pub ready: bool,
+    /// set once the peer has acknowledged our SETTINGS
+    pub settings_acked: bool,
+    /// whether the encoder adds headers to its dynamic table, see `limit_encoder_table`
+    pub encoder_indexing: bool,
+    /// the next header block has to start with a dynamic table size update to 0, followed
+    /// by one to the given size unless that's 0, see `limit_encoder_table`
+    pub encoder_size_update: Option<u32>,
+    pub stats: Arc<StatsCounters>,
+    /// highest stream ID initiated by the peer, for GOAWAY
+    pub last_peer_stream: StreamId,
+    /// set once the peer has sent a GOAWAY
+    pub going_away: bool,
+    /// set on connection errors, the connection is closed once the GOAWAY is flushed
+    pub closing: bool,
+    /// while a header block is incomplete: the stream the CONTINUATIONs must arrive on,
+    /// and the stream they belong to, which differ for PUSH_PROMISE
+    pub continuation: Option<(NonZeroStreamId, NonZeroStreamId)>,
+    /// bytes written to the socket over the lifetime of the connection
+    pub bytes_flushed: u64,
+    /// upload progress to report once `bytes_flushed` reaches the offset:
+    /// (offset, callback, bytes of the body sent, total body size)
+    #[derivative(Debug = "ignore")]
+    pub upload_progress: VecDeque<(u64, Progress, u64, u64)>,
+    /// PING payloads are the nanoseconds since this
+    pub ping_epoch: Instant,
+    /// waiting for the ACK of the PING with the payload
+    pub pending_pings: Vec<(u64, oneshot::Sender<Duration>)>,
+    /// PINGs with a payload chosen by the user: (payload, when it was sent, waiting for the ACK)
+    pub user_pings: Vec<([u8; 8], Instant, oneshot::Sender<Duration>)>,
 }
 
 impl Default for ConnectionState {
     #[must_use]
     fn default() -> Self {
         Self {
-            their_settings: enum_map! {
-                SettingsParameter::HeaderTableSize => 4096,
-                SettingsParameter::EnablePush => 1,
-                SettingsParameter::MaxConcurrentStreams => u32::MAX,
-                SettingsParameter::InitialWindowSize => 65_535,
-                SettingsParameter::MaxFrameSize => 16_384,
-                SettingsParameter::MaxHeaderListSize => u32::MAX,
-            },
+            options: ConnectionOptions::default(),
+            their_settings: initial_settings(),
             window_remaining: 65_535,
+            receive_window: ReceiveWindow::new(ConnectionOptions::default().max_receive_window),
             header_encoder: hpack::Encoder::new(),
             header_decoder: hpack::Decoder::new(),
             read_buf: BytesMut::with_capacity(16_384 + FrameHeader::SIZE),
             write_buf: BytesMut::with_capacity(16_384 + FrameHeader::SIZE),
             header: None,
             ready: false,
+            settings_acked: false,
+            encoder_indexing: true,
+            encoder_size_update: None,
+            stats: Arc::default(),
+            last_peer_stream: 0,
+            going_away: false,
+            closing: false,
+            continuation: None,
+            bytes_flushed: 0,
+            upload_progress: VecDeque::new(),
+            ping_epoch: Instant::now(),
+            pending_pings: Vec::new(),
+            user_pings: Vec::new(),
+        }
+    }
+}
+
+impl ConnectionState {
+    /// The largest dynamic table size the peer's encoder may switch to. Until our SETTINGS
+    /// are acknowledged it may not have seen our SETTINGS_HEADER_TABLE_SIZE yet.
+    pub fn header_table_limit(&self) -> usize {
+        let ours = self.options.settings[SettingsParameter::HeaderTableSize];
+        if self.settings_acked {
+            ours as usize
+        } else {
+            ours.max(initial_settings()[SettingsParameter::HeaderTableSize]) as usize
+        }
+    }
+
+    /// The receive window each stream starts with. Until our SETTINGS are acknowledged the
+    /// peer may still hold the stream to the default SETTINGS_INITIAL_WINDOW_SIZE.
+    pub fn stream_receive_window(&self) -> u32 {
+        let ours = self.options.settings[SettingsParameter::InitialWindowSize];
+        if self.settings_acked {
+            ours
+        } else {
+            ours.min(initial_settings()[SettingsParameter::InitialWindowSize])
+        }
+    }
+
+    /// The encoder's dynamic table is always 4096 octets, so while the table size we allow it,
+    /// or the peer's SETTINGS_HEADER_TABLE_SIZE, is smaller, it doesn't index, and the peer is
+    /// told to drop its copy of the table. Once both allow it again, indexing starts over
+    /// with an empty table, and the peer's copy is cleared and sized back up to match.
+    pub fn limit_encoder_table(&mut self) {
+        let size = initial_settings()[SettingsParameter::HeaderTableSize];
+        let limit = self
+            .options
+            .encoder_table_size
+            .min(self.their_settings[SettingsParameter::HeaderTableSize]);
+        let indexing = limit >= size;
+        if indexing != self.encoder_indexing {
+            self.encoder_indexing = indexing;
+            if indexing {
+                self.header_encoder = hpack::Encoder::new();
+            }
+            self.encoder_size_update = Some(if indexing { size } else { 0 });
+        }
+    }
+
+    /// https://httpwg.org/specs/rfc7540.html#ConnectionErrorHandler
+    pub fn connection_error(&mut self, error: ErrorType, debug: &'static [u8]) {
+        FramePayload::GoAway {
+            last_stream: self.last_peer_stream,
+            error,
+            debug: Bytes::from_static(debug),
+        }
+        .send(self, None, Flags::None);
+        self.closing = true;
+    }
+
+    /// Closes the connection with the GOAWAY code for the error, and its message as debug data.
+    fn frame_error(&mut self, error: FrameDecodeError) {
+        warn!("Connection error: {}", error);
+        let debug = Bytes::from(error.to_string());
+        FramePayload::GoAway {
+            last_stream: self.last_peer_stream,
+            error: error.into(),
+            debug,
+        }
+        .send(self, None, Flags::None);
+        self.closing = true;
+    }
+
+    /// Decodes the next frame header from `read_buf`, rejecting frames larger than we allow,
+    /// and a server connection preface that doesn't start with SETTINGS.
+    fn read_header(&mut self) -> Result<FrameHeader, FrameDecodeError> {
+        let header = FrameHeader::try_from(&mut self.read_buf)?;
+        self.observe(Direction::Received, &header);
+        // https://httpwg.org/specs/rfc7540.html#ConnectionHeader
+        let settings =
+            matches!(header.flags, Flags::Settings(flags) if !flags.contains(SettingsFlags::ACK));
+        if !self.ready && !settings {
+            return Err(FrameDecodeError::InvalidPreface(header.ty));
+        }
+        // https://httpwg.org/specs/rfc7540.html#FrameSize
+        if header.length > self.options.settings[SettingsParameter::MaxFrameSize] as usize {
+            return Err(FrameDecodeError::FrameTooLarge(header.length));
+        }
+        Ok(header)
+    }
+
+    #[inline]
+    pub fn observe(&self, direction: Direction, header: &FrameHeader) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            ?direction,
+            ty = ?header.ty,
+            stream_id = header.stream_id,
+            length = header.length,
+            flags = header.flags.bits(),
+            "frame"
+        );
+        if let Some(ref observer) = self.options.frame_observer {
+            observer(&FrameInfo::new(direction, header));
+        }
+    }
+
+    pub fn dump(&self, direction: Direction, header: &FrameHeader, payload: &FramePayload) {
+        if let Some(ref dump) = self.options.frame_dump {
+            let mut out = dump.lock().expect("frame dump lock");
+            let elapsed = self.ping_epoch.elapsed();
+            if let Err(err) = dump_frame(&mut *out, elapsed, direction, header, payload) {
+                debug!("Writing the frame dump: {}", err);
+            }
+        }
+    }
+
+    /// Sends a PING carrying the time it was sent, returns the payload.
+    pub fn send_ping(&mut self) -> u64 {
+        let sent = u64::try_from(self.ping_epoch.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        FramePayload::Ping {
+            data: Bytes::copy_from_slice(&sent.to_be_bytes()),
+        }
+        .send(self, None, PingFlags::empty());
+        sent
+    }
+
+    /// Updates the smoothed RTT like TCP does, https://www.rfc-editor.org/rfc/rfc6298.html#section-2
+    fn ping_acked(&mut self, data: &[u8]) {
+        let Ok(payload) = data.try_into() else {
+            return;
+        };
+        // the payload of a user's PING isn't a timestamp, so it isn't an RTT sample
+        if self.user_pings.iter().any(|(user, ..)| *user == payload) {
+            let (acked, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.user_pings)
+                .into_iter()
+                .partition(|(user, ..)| *user == payload);
+            self.user_pings = pending;
+            for (_, sent, rtt_tx) in acked {
+                rtt_tx.send(sent.elapsed()).ok();
+            }
+            return;
+        }
+        let sent = u64::from_be_bytes(payload);
+        let Some(rtt) = self
+            .ping_epoch
+            .elapsed()
+            .checked_sub(Duration::from_nanos(sent))
+        else {
+            warn!("PING ACK with a payload we didn't send");
+            return;
+        };
+        let sample = u64::try_from(rtt.as_nanos()).unwrap_or(u64::MAX);
+        let smoothed = match self.stats.rtt.load(Ordering::Relaxed) {
+            0 => sample,
+            previous => previous - previous / 8 + sample / 8,
+        };
+        self.stats.rtt.store(smoothed.max(1), Ordering::Relaxed);
+        if let Some(increment) = self.receive_window.ping_acked(sent, rtt) {
+            FramePayload::WindowUpdate { increment }.send(self, None, Flags::None);
+        }
+        trace!(
+            "PING RTT {:?}, smoothed {:?}",
+            rtt,
+            Duration::from_nanos(smoothed)
+        );
+
+        let (acked, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_pings)
+            .into_iter()
+            .partition(|(payload, _)| *payload == sent);
+        self.pending_pings = pending;
+        for (_, rtt_tx) in acked {
+            rtt_tx.send(rtt).ok();
+        }
+    }
+
+    /// Reports the upload progress of everything that the written bytes completed.
+    pub fn flushed(&mut self, written: usize) {
+        self.stats
+            .bytes_sent
+            .fetch_add(written as u64, Ordering::Relaxed);
+        self.bytes_flushed += written as u64;
+        while matches!(self.upload_progress.front(), Some((offset, ..)) if *offset <= self.bytes_flushed)
+        {
+            if let Some((_, progress, sent, total)) = self.upload_progress.pop_front() {
+                progress(sent, Some(total));
+            }
         }
     }
 }
 
 static CLIENT_CONNECTION_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
+#[derive(Debug)]
+pub enum Message {
+    Request(Request, oneshot::Sender<Result<Response, RequestError>>),
+    /// a CONNECT tunnel, or any request with a streamed response body
+    Tunnel {
+        request: Request,
+        response_tx: oneshot::Sender<Result<Response, RequestError>>,
+        stream_id_tx: oneshot::Sender<NonZeroStreamId>,
+        data_tx: mpsc::UnboundedSender<Result<Bytes, RequestError>>,
+    },
+    TunnelData {
+        stream_id: NonZeroStreamId,
+        data: Bytes,
+        end_stream: bool,
+        /// resolved once the data has been sent within the flow-control windows
+        written_tx: oneshot::Sender<()>,
+    },
+    /// bytes written as they are, bypassing the frame and stream state
+    #[cfg(feature = "unstable")]
+    Raw {
+        data: Bytes,
+    },
+    Shutdown {
+        timeout: Duration,
+        done_tx: oneshot::Sender<()>,
+    },
+    /// a response was dropped before it arrived, see `CancelOnDrop`
+    Cancelled,
+    Ping {
+        /// chosen by the user, instead of a timestamp that the RTT estimate is updated with
+        payload: Option<[u8; 8]>,
+        rtt_tx: oneshot::Sender<Duration>,
+    },
+}
+
+/// A TLS connection that HTTP/2 hasn't been started on yet.
+pub struct Handshake {
+    pub stream: TlsStream,
+    pub timing: Timing,
+    pub tls_session: TlsSession,
+}
+
+impl Handshake {
+    /// Whether the server chose HTTP/1.1 over HTTP/2 with ALPN.
+    #[inline]
+    pub fn is_http1(&self) -> bool {
+        self.stream.alpn_protocol().as_deref() == Some(b"http/1.1")
+    }
+}
+
+/// A handle to the connection task; clones share the same connection.
+#[derive(Clone)]
 pub struct Connection {
-    requests: mpsc::Sender<(Request, oneshot::Sender<Response>)>,
+    requests: mpsc::Sender<Message>,
+    timing: Timing,
+    tls_session: TlsSession,
+    stats: Arc<StatsCounters>,
+    /// when the connection stops taking new streams, see `ConnectionOptions::max_lifetime`
+    expires: Option<Instant>,
+    /// the server's address, unknown through a proxy
+    peer_addr: Option<SocketAddr>,
+    /// the server's end-entity certificate in DER
+    certificate: Option<Arc<[u8]>>,
 }
 
 impl Connection {
-    pub async fn connect(url: &Url, connector: &TlsConnector) -> anyhow::Result<Self> {
+    /// Connects and completes the TLS handshake, with the connection preface as early data
+    /// if the session is resumed.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(origin = %url.origin().ascii_serialization()))
+    )]
+    pub async fn handshake(
+        url: &Url,
+        connector: &Connector,
+        options: &ConnectionOptions,
+    ) -> anyhow::Result<Handshake> {
+        let start = Instant::now();
+        check_scheme(url)?;
+        let host = ascii_host(url)?;
+        let (resolved, tcp) = if let Some(ref proxy) = options.proxy {
+            proxy::check_scheme(proxy)?;
+            let addrs = resolve(proxy, options).await?;
+            let resolved = Instant::now();
+            let tcp = tcp::connect(addrs, options.local_addr).await?;
+            (resolved, proxy::connect(tcp, proxy, url).await?)
+        } else {
+            let addrs = resolve(url, options).await?;
+            let resolved = Instant::now();
+            (resolved, tcp::connect(addrs, options.local_addr).await?)
+        };
+        let connected = Instant::now();
+
+        let server_name = options.server_names.get(&host).unwrap_or(&host);
+        let (stream, tls_session) = match connector {
+            Connector::Rustls(connector) => {
+                Self::rustls_handshake(connector, server_name, tcp, options).await?
+            }
+            #[cfg(feature = "native-tls")]
+            Connector::NativeTls(connector) => (
+                tls::connect_native_tls(connector, server_name, tcp, &options.pins).await?,
+                TlsSession::default(),
+            ),
+        };
+        debug!("{:?}", tls_session);
+        let timing = Timing {
+            dns: Some(resolved - start),
+            connect: Some(connected - resolved),
+            tls: Some(connected.elapsed()),
+            ..Timing::default()
+        };
+        Ok(Handshake {
+            stream,
+            timing,
+            tls_session,
+        })
+    }
+
+    /// The rustls handshake, with the connection preface as early data if it's enabled and
+    /// the session is resumed.
+    async fn rustls_handshake(
+        connector: &tokio_rustls::TlsConnector,
+        server_name: &str,
+        tcp: TcpStream,
+        options: &ConnectionOptions,
+    ) -> anyhow::Result<(TlsStream, TlsSession)> {
         let mut early_data_sent = false;
-        let mut stream = connector
+        let stream = connector
             .connect_with(
-                url.host_str()
-                    .ok_or_else(|| anyhow!("connect host name"))?
+                server_name
                     .try_into()
                     .map_err(|err| anyhow!("connect host name into server name: {:?}", err))?,
-                TcpStream::connect(url.socket_addrs(|| None)?[0]).await?,
+                tcp,
                 |connection| {
                     use std::io::Write;
+                    // with HTTP/1.1 on offer, the preface may be for the wrong protocol
+                    if !options.early_data || options.http1_fallback {
+                        return;
+                    }
                     if let Some(mut early) = connection.early_data() {
                         if early.bytes_left() >= CLIENT_CONNECTION_PREFACE.len() {
                             if let Err(err) = early.write_all(CLIENT_CONNECTION_PREFACE) {
@@ -81,76 +602,341 @@ impl Connection {
                     }
                 },
             )
-            .await?;
+            .await
+            .map_err(tls::handshake_error)?;
+        let tls_session = TlsSession {
+            early_data_sent,
+            early_data_accepted: early_data_sent && stream.get_ref().1.is_early_data_accepted(),
+        };
+        Ok((TlsStream::Rustls(Box::new(stream)), tls_session))
+    }
+
+    /// Starts HTTP/2 on the connection, and the task driving it.
+    pub async fn start(handshake: Handshake, options: &ConnectionOptions) -> anyhow::Result<Self> {
+        let Handshake {
+            stream,
+            timing,
+            tls_session,
+        } = handshake;
+        let peer_addr = if options.proxy.is_some() {
+            None
+        } else {
+            stream.peer_addr()
+        };
+        let certificate = stream.peer_certificate();
+        Self::run(stream, options, timing, tls_session, peer_addr, certificate).await
+    }
 
-        if !early_data_sent || !stream.get_ref().1.is_early_data_accepted() {
+    /// Starts HTTP/2 over any transport that's already connected, e.g. one half of a
+    /// `tokio::io::duplex` pair standing in for a server in tests.
+    pub async fn from_io(
+        io: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        options: &ConnectionOptions,
+    ) -> anyhow::Result<Self> {
+        Self::run(
+            io,
+            options,
+            Timing::default(),
+            TlsSession::default(),
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Writes the connection preface, unless it was sent as early data, and spawns the task.
+    async fn run(
+        mut stream: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        options: &ConnectionOptions,
+        timing: Timing,
+        tls_session: TlsSession,
+        peer_addr: Option<SocketAddr>,
+        certificate: Option<Arc<[u8]>>,
+    ) -> anyhow::Result<Self> {
+        if !tls_session.early_data_accepted {
             stream.write_all(CLIENT_CONNECTION_PREFACE).await?;
         }
-
         let (mut reader, mut writer) = split(stream);
         let (requests_tx, mut requests_rx) =
-            mpsc::channel::<(Request, oneshot::Sender<Response>)>(16);
+            mpsc::channel::<Message>(options.request_channel_capacity.max(1));
+
+        let mut state = ConnectionState {
+            options: options.clone(),
+            receive_window: ReceiveWindow::new(options.max_receive_window),
+            ..ConnectionState::default()
+        };
+        state.limit_encoder_table();
+        let stats = Arc::clone(&state.stats);
+        let expires = options
+            .max_lifetime
+            .map(|lifetime| Instant::now() + jittered(lifetime));
+        stats.max_concurrent_streams.store(
+            state.their_settings[SettingsParameter::MaxConcurrentStreams],
+            Ordering::Relaxed,
+        );
+        *stats.peer_settings.lock().expect("settings lock") = state.their_settings;
 
-        tokio::spawn(async move {
-            let mut state = ConnectionState::default();
+        let task = async move {
             let mut streams = StreamCoordinator::default();
+            let mut shutdown: Option<(Instant, oneshot::Sender<()>)> = None;
+            let mut next_ping = state
+                .options
+                .ping_interval
+                .map(|interval| Instant::now() + interval);
+            let mut last_activity = Instant::now();
+            let mut expired = false;
 
             loop {
+                streams.schedule_data(&mut state);
+                let headers_deadline = state
+                    .options
+                    .headers_timeout
+                    .and_then(|timeout| streams.headers_deadline(timeout));
+                let continue_deadline =
+                    streams.continue_deadline(state.options.expect_continue_timeout);
+                let idle_deadline = state
+                    .options
+                    .idle_timeout
+                    .filter(|_| shutdown.is_none() && streams.active() == 0)
+                    .map(|timeout| last_activity + timeout);
+                let expires = expires.filter(|_| !expired);
                 tokio::select! {
                     res = reader.read_buf(&mut state.read_buf) => {
-                        res.expect("read_buf");
+                        last_activity = Instant::now();
+                        let read = match res {
+                            Ok(0) if state.header.is_some() || !state.read_buf.is_empty() => {
+                                warn!("Connection closed by peer in the middle of a frame");
+                                break;
+                            }
+                            Ok(0) => {
+                                debug!("Connection closed by peer");
+                                break;
+                            }
+                            Ok(read) => read,
+                            Err(err) => {
+                                warn!("Failed to read: {:?}", err);
+                                break;
+                            }
+                        };
+                        state.stats.bytes_received.fetch_add(read as u64, Ordering::Relaxed);
                         loop {
-                            if let Some(ref header) = state.header {
-                                match FramePayload::try_from(&mut state.read_buf, header) {
-                                    Ok(payload) => {
-                                        Self::handle_frame(&mut state, &mut streams, payload).expect("handle_frame");
-                                        state.header = None;
-                                    },
-                                    Err(DecodeError::TooShort) => {
-                                        break;
-                                    }
-                                    err @ Err(_) => {
-                                        err.expect("FramePayload::try_from");
-                                    },
-                                }
+                            let result = if let Some(ref header) = state.header {
+                                FramePayload::try_from(&mut state.read_buf, header)
+                                    .inspect(|payload| state.dump(Direction::Received, header, payload))
+                                    .and_then(|payload| {
+                                    let result = Self::handle_frame(&mut state, &mut streams, payload);
+                                    state.header = None;
+                                    result
+                                })
                             } else {
-                                match FrameHeader::try_from(&mut state.read_buf) {
-                                  Ok(header) => { state.header = Some(header); }
-                                  Err(DecodeError::TooShort) => { break; }
-                                  err @ Err(_) => {
-                                    err.expect("FrameHeader::try_from");
-                                  }
+                                state.read_header().map(|header| {
+                                    state.stats.frames_received[header.ty].fetch_add(1, Ordering::Relaxed);
+                                    state.header = Some(header);
+                                })
+                            };
+                            match result {
+                                Ok(()) if state.closing => break,
+                                Ok(()) => {}
+                                // wait for the rest of the frame
+                                Err(FrameDecodeError::TooShort) => break,
+                                Err(err)
+                                    if err.stream_error().is_some()
+                                        && state.header.as_ref().is_some_and(|header| header.stream_id != 0) =>
+                                {
+                                    Self::stream_error(&mut state, &mut streams, &err);
+                                }
+                                Err(err) => {
+                                    state.frame_error(err);
+                                    break;
                                 }
                             }
                         }
                     }
                     res = writer.write_buf(&mut state.write_buf), if state.write_buf.has_remaining() => {
-                        res.expect("write_buf");
+                        match res {
+                            Ok(written) => state.flushed(written),
+                            Err(err) => {
+                                warn!("Failed to write: {:?}", err);
+                                break;
+                            }
+                        }
+                    }
+                    () = sleep_until(shutdown.as_ref().map_or_else(Instant::now, |(deadline, _)| *deadline)), if shutdown.is_some() => {
+                        warn!("Shutting down with {} streams still active", streams.active());
+                        break;
+                    }
+                    () = sleep_until(headers_deadline.unwrap_or_else(Instant::now)), if headers_deadline.is_some() => {
+                        if let Some(timeout) = state.options.headers_timeout {
+                            streams.time_out_headers(&mut state, timeout);
+                        }
+                    }
+                    () = sleep_until(continue_deadline.unwrap_or_else(Instant::now)), if continue_deadline.is_some() => {
+                        streams.time_out_continue(state.options.expect_continue_timeout);
+                    }
+                    () = sleep_until(next_ping.unwrap_or_else(Instant::now)), if next_ping.is_some() => {
+                        state.send_ping();
+                        next_ping = state.options.ping_interval.map(|interval| Instant::now() + interval);
+                    }
+                    () = sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                        // a request may be on its way through the channel
+                        if state.stats.in_flight.load(Ordering::Relaxed) > 0 {
+                            last_activity = Instant::now();
+                        } else {
+                            debug!("Closing idle connection");
+                            FramePayload::GoAway {
+                                last_stream: state.last_peer_stream,
+                                error: ErrorType::NoError,
+                                debug: Bytes::new(),
+                            }
+                            .send(&mut state, None, Flags::None);
+                            break;
+                        }
+                    }
+                    () = sleep_until(expires.unwrap_or_else(Instant::now)), if expires.is_some() => {
+                        debug!("Connection reached its max lifetime, draining {} streams", streams.active());
+                        FramePayload::GoAway {
+                            last_stream: state.last_peer_stream,
+                            error: ErrorType::NoError,
+                            debug: Bytes::new(),
+                        }
+                        .send(&mut state, None, Flags::None);
+                        expired = true;
                     }
                     entry = requests_rx.recv(), if state.ready => {
-                        if let Some((request, response_tx)) = entry {
-                            trace!("{:#?}", request);
-                            match request.write_into(&mut state, &mut streams, response_tx) {
-                                Ok(_) => {}
-                                Err(RequestError::OutOfStreamIds) => {
-                                    warn!("Out of stream IDs");
-                                    return;
-                                }
-                                Err(err) => {
-                                    error!("Request error: {:?}", err);
+                        last_activity = Instant::now();
+                        let result = match entry {
+                            // dropped before it was even sent
+                            Some(
+                                Message::Request(_, response_tx)
+                                | Message::Tunnel { response_tx, .. }
+                            ) if response_tx.is_closed() => Ok(()),
+                            Some(
+                                Message::Request(_, response_tx)
+                                | Message::Tunnel { response_tx, .. }
+                            ) if shutdown.is_some() || state.going_away || expired => {
+                                response_tx.send(Err(RequestError::NotProcessed)).ok();
+                                Ok(())
+                            }
+                            Some(Message::Request(request, response_tx)) => {
+                                trace!("{:#?}", request);
+                                request.write_into(&mut state, &mut streams, response_tx).map(|_| ())
+                            }
+                            Some(Message::Tunnel { request, response_tx, stream_id_tx, data_tx }) => {
+                                trace!("{:#?}", request);
+                                request.write_into(&mut state, &mut streams, response_tx).map(|stream_id| {
+                                    streams.get_mut(stream_id).data_tx = Some(data_tx);
+                                    stream_id_tx.send(stream_id).ok();
+                                })
+                            }
+                            Some(Message::TunnelData { stream_id, data, end_stream, written_tx }) => {
+                                streams.queue_data(stream_id, data, end_stream);
+                                streams.when_written(stream_id, written_tx);
+                                Ok(())
+                            }
+                            Some(Message::Ping { payload: Some(payload), rtt_tx }) => {
+                                FramePayload::Ping { data: Bytes::copy_from_slice(&payload) }
+                                    .send(&mut state, None, PingFlags::empty());
+                                state.user_pings.push((payload, Instant::now(), rtt_tx));
+                                Ok(())
+                            }
+                            Some(Message::Ping { payload: None, rtt_tx }) => {
+                                let sent = state.send_ping();
+                                state.pending_pings.push((sent, rtt_tx));
+                                Ok(())
+                            }
+                            #[cfg(feature = "unstable")]
+                            Some(Message::Raw { data }) => {
+                                state.write_buf.extend_from_slice(&data);
+                                Ok(())
+                            }
+                            Some(Message::Shutdown { timeout, done_tx }) => {
+                                FramePayload::GoAway {
+                                    last_stream: state.last_peer_stream,
+                                    error: ErrorType::NoError,
+                                    debug: Bytes::new(),
                                 }
+                                .send(&mut state, None, Flags::None);
+                                shutdown = Some((Instant::now() + timeout, done_tx));
+                                Ok(())
+                            }
+                            Some(Message::Cancelled) => {
+                                streams.cancel_abandoned(&mut state);
+                                Ok(())
+                            }
+                            None => {
+                                // end task if no one can send any requests anymore
+                                return;
+                            }
+                        };
+                        match result {
+                            Ok(_) => {}
+                            Err(RequestError::OutOfStreamIds) => {
+                                warn!("Out of stream IDs");
+                                return;
+                            }
+                            Err(err) => {
+                                error!("Request error: {:?}", err);
                             }
-                        } else {
-                            // end task if no one can send any requests anymore
-                            return;
                         }
                     }
                 }
+
+                streams.prune_closed();
+                state
+                    .stats
+                    .active_streams
+                    .store(streams.active(), Ordering::Relaxed);
+                state
+                    .stats
+                    .total_streams
+                    .store(streams.total(), Ordering::Relaxed);
+                state
+                    .stats
+                    .send_window
+                    .store(state.window_remaining, Ordering::Relaxed);
+
+                if state.closing
+                    || (shutdown.is_some() || state.going_away || expired) && streams.active() == 0
+                {
+                    break;
+                }
             }
-        });
+
+            streams.connection_lost();
+            // the requests still queued were never sent
+            requests_rx.close();
+            while let Ok(message) = requests_rx.try_recv() {
+                if let Message::Request(_, response_tx) | Message::Tunnel { response_tx, .. } =
+                    message
+                {
+                    response_tx.send(Err(RequestError::NotProcessed)).ok();
+                }
+            }
+
+            // flush whatever is left, at least the GOAWAY
+            if let Err(err) = writer.write_all_buf(&mut state.write_buf).await {
+                warn!("Failed to flush on shutdown: {:?}", err);
+            }
+            writer.shutdown().await.ok();
+            if let Some((_, done_tx)) = shutdown {
+                done_tx.send(()).ok();
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let task = tracing::Instrument::instrument(
+            task,
+            tracing::debug_span!("connection", peer = ?peer_addr),
+        );
+        tokio::spawn(task);
 
         Ok(Self {
             requests: requests_tx,
+            timing,
+            tls_session,
+            stats,
+            expires,
+            peer_addr,
+            certificate,
         })
     }
 
@@ -158,83 +944,198 @@ impl Connection {
         state: &mut ConnectionState,
         streams: &mut StreamCoordinator,
         payload: FramePayload,
-    ) -> anyhow::Result<()> {
-        let header = state
-            .header
-            .as_ref()
-            .ok_or_else(|| anyhow!("no header for payload"))?;
+    ) -> Result<(), FrameDecodeError> {
+        let header = state.header.clone().expect("header for payload");
+
+        // https://httpwg.org/specs/rfc7540.html#FrameTypes
+        let connection_level = header.stream_id == 0;
+        let valid_stream = match header.ty {
+            FrameType::Settings | FrameType::Ping | FrameType::GoAway => connection_level,
+            // ignored on other streams
+            FrameType::WindowUpdate
+            | FrameType::AltSvc
+            | FrameType::Origin
+            | FrameType::Unknown => true,
+            FrameType::Data
+            | FrameType::Headers
+            | FrameType::Priority
+            | FrameType::ResetStream
+            | FrameType::PushPromise
+            | FrameType::Continuation => !connection_level,
+        };
+        if !valid_stream {
+            state.connection_error(
+                ErrorType::ProtocolError,
+                if connection_level {
+                    b"frame type not allowed on stream 0"
+                } else {
+                    b"frame type only allowed on stream 0"
+                },
+            );
+            return Ok(());
+        }
+
+        // https://httpwg.org/specs/rfc7540.html#CONTINUATION
+        let continuation_target = match state.continuation {
+            Some((stream_id, target))
+                if header.ty == FrameType::Continuation && header.stream_id == stream_id.get() =>
+            {
+                Some(target)
+            }
+            None if header.ty != FrameType::Continuation => None,
+            _ => {
+                state.connection_error(ErrorType::ProtocolError, b"invalid CONTINUATION sequence");
+                return Ok(());
+            }
+        };
+
         match (header.flags, payload) {
             (Flags::Settings(flags), FramePayload::Settings { params, .. }) => {
-                if !flags.contains(SettingsFlags::ACK) {
+                if flags.contains(SettingsFlags::ACK) {
+                    state.settings_acked = true;
+                } else {
                     for (key, value) in params {
+                        if key == SettingsParameter::InitialWindowSize
+                            && (value > U31_MAX.get() || !streams.set_initial_window(value))
+                        {
+                            state.connection_error(
+                                ErrorType::FlowControlError,
+                                b"initial window size too large",
+                            );
+                            return Ok(());
+                        }
                         state.their_settings[key] = value;
                     }
+                    state.stats.max_concurrent_streams.store(
+                        state.their_settings[SettingsParameter::MaxConcurrentStreams],
+                        Ordering::Relaxed,
+                    );
+                    state.limit_encoder_table();
+                    *state.stats.peer_settings.lock().expect("settings lock") =
+                        state.their_settings;
                     if !state.ready {
+                        let initial = initial_settings();
                         FramePayload::Settings {
-                            params: vec![(SettingsParameter::InitialWindowSize, U31_MAX.get())],
+                            // only the ones that differ from the initial values need to be sent
+                            params: state
+                                .options
+                                .settings
+                                .iter()
+                                .filter(|&(key, value)| initial[key] != *value)
+                                .map(|(key, &value)| (key, value))
+                                .collect(),
                         }
-                        .write_into(
-                            &mut state.write_buf,
-                            None,
-                            Flags::None,
-                        );
+                        .send(state, None, Flags::None);
                         state.ready = true;
                     }
-                    FramePayload::Settings { params: Vec::new() }.write_into(
-                        &mut state.write_buf,
+                    FramePayload::Settings { params: Vec::new() }.send(
+                        state,
                         None,
                         SettingsFlags::ACK,
                     );
                 }
             }
             (Flags::Ping(flags), FramePayload::Ping { data, .. }) => {
-                if !flags.contains(PingFlags::ACK) {
-                    if data.len() == 8 {
-                        FramePayload::Ping { data }.write_into(
-                            &mut state.write_buf,
-                            None,
-                            PingFlags::ACK,
-                        );
-                    } else {
-                        FramePayload::GoAway {
-                            last_stream: 0,
-                            error: ErrorType::ProtocolError,
-                            debug: Bytes::from_static(b"invalid ping payload length"),
-                        }
-                        .write_into(
-                            &mut state.write_buf,
-                            None,
-                            Flags::None,
-                        );
-                    }
+                // the length was checked when decoding
+                if flags.contains(PingFlags::ACK) {
+                    state.ping_acked(&data);
+                } else {
+                    FramePayload::Ping { data }.send(state, None, PingFlags::ACK);
                 }
             }
-            (_, FramePayload::GoAway { error, debug, .. }) => {
+            (
+                _,
+                FramePayload::GoAway {
+                    last_stream,
+                    error,
+                    debug,
+                },
+            ) => {
                 error!("Go away: {:?}", error);
+                state.going_away = true;
+                state.stats.going_away.store(true, Ordering::Relaxed);
+                streams.fail_above(last_stream, RequestError::NotProcessed);
                 if !debug.is_empty() {
                     if let Ok(debug) = std::str::from_utf8(&debug) {
                         debug!("Go away debug: {}", debug);
                     }
                 }
             }
+            (_, FramePayload::AltSvc { origin, value }) => {
+                // https://www.rfc-editor.org/rfc/rfc7838.html#section-4
+                // the origin is only given on stream 0, other invalid ones are ignored
+                if connection_level != origin.is_empty() && !value.is_empty() {
+                    let origin = Some(origin).filter(|_| connection_level);
+                    let mut alt_svc = state.stats.alt_svc.lock().expect("alt-svc lock");
+                    alt_svc.retain(|alt_svc| alt_svc.origin != origin);
+                    alt_svc.push(AltSvc { origin, value });
+                }
+            }
+            (_, FramePayload::Origin { origins }) => {
+                // https://www.rfc-editor.org/rfc/rfc8336.html#section-2.1
+                if connection_level {
+                    let mut origin_set = state.stats.origins.lock().expect("origins lock");
+                    for origin in origins {
+                        if !origin_set.contains(&origin) {
+                            origin_set.push(origin);
+                        }
+                    }
+                }
+            }
+            // https://httpwg.org/specs/rfc9113.html#FrameHeader
+            (_, FramePayload::Unknown) => {
+                trace!(
+                    "Ignored frame of unknown type on stream {}",
+                    header.stream_id
+                );
+            }
             (_, FramePayload::WindowUpdate { increment, .. }) => {
                 if let Some(stream_id) = NonZeroStreamId::new(header.stream_id) {
-                    streams
-                        .get_mut(stream_id)
-                        .handle_frame(state, FramePayload::WindowUpdate { increment })?;
+                    if let Some(stream) = streams.existing_mut(stream_id) {
+                        stream.handle_frame(state, FramePayload::WindowUpdate { increment })?;
+                    } else {
+                        Self::unknown_stream(
+                            state,
+                            streams,
+                            stream_id,
+                            FramePayload::WindowUpdate { increment },
+                        )?;
+                    }
                 } else {
-                    state.window_remaining = state
-                        .window_remaining
-                        .saturating_add(increment.get() as usize);
+                    // https://httpwg.org/specs/rfc7540.html#fc-conn-window
+                    let window = state.window_remaining + increment.get() as usize;
+                    if window > U31_MAX.get() as usize {
+                        state.connection_error(
+                            ErrorType::FlowControlError,
+                            b"connection window overflow",
+                        );
+                        return Ok(());
+                    }
+                    state.window_remaining = window;
                 }
             }
             (
-                _,
+                Flags::PushPromise(flags),
                 FramePayload::PushPromise {
                     promised_stream,
                     fragment,
                 },
             ) => {
+                // https://httpwg.org/specs/rfc7540.html#PUSH_PROMISE
+                // pushes are promised on streams we've opened, and must use new even IDs
+                let associated = NonZeroStreamId::new(header.stream_id)
+                    .filter(|&stream_id| streams.existing_mut(stream_id).is_some());
+                if associated.is_none()
+                    || promised_stream.get() % 2 != 0
+                    || promised_stream.get() <= state.last_peer_stream
+                {
+                    state.connection_error(ErrorType::ProtocolError, b"invalid PUSH_PROMISE");
+                    return Ok(());
+                }
+                if !flags.contains(PushPromiseFlags::END_HEADERS) {
+                    state.continuation = associated.map(|stream_id| (stream_id, promised_stream));
+                }
+                state.last_peer_stream = state.last_peer_stream.max(promised_stream.get());
                 let stream = streams.get_mut(promised_stream);
                 stream.handle_frame(
                     state,
@@ -244,20 +1145,381 @@ impl Connection {
                     },
                 )?;
             }
+            (Flags::Continuation(flags), payload @ FramePayload::Continuation { .. }) => {
+                if flags.contains(ContinuationFlags::END_HEADERS) {
+                    state.continuation = None;
+                }
+                // checked above
+                let target = continuation_target.expect("continuation target");
+                streams.get_mut(target).handle_frame(state, payload)?;
+                streams.release_withheld(target);
+            }
             (_, payload) => {
-                streams
-                    .get_mut(
-                        NonZeroStreamId::new(header.stream_id).ok_or(DecodeError::ZeroStreamId)?,
-                    )
-                    .handle_frame(state, payload)?;
+                let stream_id =
+                    NonZeroStreamId::new(header.stream_id).ok_or(FrameDecodeError::ZeroStreamId)?;
+                let priority = match payload {
+                    FramePayload::Priority {
+                        dependency,
+                        exclusive_dependency,
+                        weight,
+                    }
+                    | FramePayload::Headers {
+                        dependency: Some(dependency),
+                        exclusive_dependency: Some(exclusive_dependency),
+                        weight: Some(weight),
+                        ..
+                    } => Some((dependency, exclusive_dependency, weight)),
+                    _ => None,
+                };
+                if let Some((dependency, exclusive, weight)) = priority {
+                    streams.reprioritize(state, stream_id, dependency, exclusive, weight);
+                }
+                if let Some(stream) = streams.existing_mut(stream_id) {
+                    if let Flags::Headers(flags) = header.flags {
+                        if !flags.contains(HeadersFlags::END_HEADERS) {
+                            state.continuation = Some((stream_id, stream_id));
+                        }
+                    }
+                    stream.handle_frame(state, payload)?;
+                    streams.release_withheld(stream_id);
+                } else {
+                    Self::unknown_stream(state, streams, stream_id, payload)?;
+                }
             }
         }
         Ok(())
     }
 
+    /// Resets the stream the frame was on, the connection carries on.
+    /// https://httpwg.org/specs/rfc7540.html#StreamErrorHandler
+    fn stream_error(
+        state: &mut ConnectionState,
+        streams: &mut StreamCoordinator,
+        error: &FrameDecodeError,
+    ) {
+        warn!("Stream error: {}", error);
+        let header = state.header.take().expect("header for payload");
+        let stream = NonZeroStreamId::new(header.stream_id).and_then(|id| streams.existing_mut(id));
+        if let Some(stream) = stream {
+            let error = error.stream_error().expect("stream error");
+            stream.reset(state, error, RequestError::Reset(error));
+        }
+    }
+
+    /// Frames on streams we haven't opened and that haven't been reserved by a PUSH_PROMISE,
+    /// or on closed streams that have been forgotten.
+    /// https://httpwg.org/specs/rfc7540.html#StreamIdentifiers
+    /// https://httpwg.org/specs/rfc7540.html#StreamStates
+    fn unknown_stream(
+        state: &mut ConnectionState,
+        streams: &StreamCoordinator,
+        stream_id: NonZeroStreamId,
+        payload: FramePayload,
+    ) -> Result<(), FrameDecodeError> {
+        let header = state.header.clone().expect("header for payload");
+        match payload {
+            // can be sent for any stream, but we don't do anything with it for unknown ones
+            FramePayload::Priority { .. } => return Ok(()),
+            _ if !streams.was_opened(stream_id) => {
+                state.connection_error(ErrorType::ProtocolError, b"frame on an idle stream");
+                return Ok(());
+            }
+            // still counts against the connection window
+            FramePayload::Data { .. } => {
+                if let Some(increment) = state.receive_window.received(header.length as u32) {
+                    FramePayload::WindowUpdate { increment }.send(state, None, Flags::None);
+                }
+            }
+            // keeps the decoder's dynamic table in step with the peer's encoder
+            FramePayload::Headers { fragment, .. } => {
+                if !matches!(header.flags, Flags::Headers(flags) if flags.contains(HeadersFlags::END_HEADERS))
+                {
+                    state.connection_error(
+                        ErrorType::CompressionError,
+                        b"header block continued on a closed stream",
+                    );
+                    return Ok(());
+                }
+                discard_header_block(state, &fragment)?;
+            }
+            _ => {}
+        }
+        if matches!(header.ty, FrameType::WindowUpdate | FrameType::ResetStream) {
+            // may have been sent before the peer saw the stream close
+            trace!("{:?} on closed stream {}", header.ty, stream_id);
+        } else if streams.was_reset(stream_id) {
+            trace!("{:?} on stream {} after resetting it", header.ty, stream_id);
+        } else {
+            debug!("{:?} on closed stream {}", header.ty, stream_id);
+            FramePayload::ResetStream {
+                error: ErrorType::StreamClosed,
+            }
+            .send(state, Some(&mut Stream::forgotten(stream_id)), Flags::None);
+        }
+        Ok(())
+    }
+
+    /// How long establishing the connection took.
+    #[inline]
+    pub fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    #[inline]
+    pub fn tls_session(&self) -> TlsSession {
+        self.tls_session
+    }
+
+    #[inline]
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
     pub async fn request(&self, request: Request) -> anyhow::Result<Response> {
+        if matches!(request.method, Method::Connect) {
+            return Err(anyhow!("CONNECT requests must be sent with tunnel()"));
+        }
+        let _in_flight = self.in_flight();
+        let cancel = self.cancel_on_drop();
         let (tx, rx) = oneshot::channel();
-        self.requests.send((request, tx)).await?;
-        Ok(rx.await?)
+        self.requests
+            .send(Message::Request(request, tx))
+            .await
+            .map_err(|_| RequestError::ConnectionClosed)?;
+        let response = rx.await;
+        cancel.disarm();
+        Ok(response.map_err(|_| RequestError::ConnectionClosed)??)
+    }
+
+    /// Whether the connection task has ended, e.g. after the idle timeout or a GOAWAY,
+    /// so that no more requests can be sent on it.
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    /// Whether the server has sent a GOAWAY, so that the connection takes no new streams.
+    #[inline]
+    pub fn is_going_away(&self) -> bool {
+        self.stats.going_away.load(Ordering::Relaxed)
+    }
+
+    /// Whether the connection has reached its max lifetime and takes no new streams.
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= Instant::now())
+    }
+
+    /// Whether both handles refer to the same connection.
+    pub(crate) fn same_connection(&self, other: &Self) -> bool {
+        self.requests.same_channel(&other.requests)
+    }
+
+    /// Counts a request as in flight until the guard is dropped.
+    fn in_flight(&self) -> InFlight {
+        self.stats.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(Arc::clone(&self.stats))
+    }
+
+    /// Cancels the request if the guard is dropped before it's disarmed, i.e. when the future
+    /// waiting for the response is.
+    fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(Some(self.requests.clone()))
+    }
+
+    /// Whether the peer's limit on concurrent streams has been reached, counting the
+    /// requests that haven't been written yet.
+    pub fn is_saturated(&self) -> bool {
+        let load = self
+            .stats
+            .in_flight
+            .load(Ordering::Relaxed)
+            .max(self.active_streams());
+        load >= self.stats.max_concurrent_streams.load(Ordering::Relaxed) as usize
+    }
+
+    /// The additional origins the server said the connection is authoritative for with
+    /// ORIGIN frames, in the ASCII serialization like `https://example.com`.
+    pub fn origins(&self) -> Vec<String> {
+        self.stats.origins.lock().expect("origins lock").clone()
+    }
+
+    /// The settings the server has sent so far, with the initial values for the rest. All of
+    /// the parameters in a SETTINGS frame take effect together.
+    pub fn peer_settings(&self) -> EnumMap<SettingsParameter, u32> {
+        *self.stats.peer_settings.lock().expect("settings lock")
+    }
+
+    /// The latest alternative service the server advertised with ALTSVC frames for each
+    /// origin, in the order they arrived.
+    pub fn alt_svc(&self) -> Vec<AltSvc> {
+        self.stats.alt_svc.lock().expect("alt-svc lock").clone()
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Whether the server's certificate is valid for the ASCII host name, so that requests
+    /// to it may be sent on this connection too.
+    ///
+    /// https://httpwg.org/specs/rfc7540.html#reuse
+    pub fn certificate_covers(&self, host: &str) -> bool {
+        let Some(ref certificate) = self.certificate else {
+            return false;
+        };
+        let Ok(certificate) = webpki::EndEntityCert::try_from(&certificate[..]) else {
+            return false;
+        };
+        // IP addresses aren't DNS names, connections to them aren't coalesced
+        webpki::DnsNameRef::try_from_ascii_str(host)
+            .is_ok_and(|name| certificate.verify_is_valid_for_dns_name(name).is_ok())
+    }
+
+    /// Streams that haven't been closed yet.
+    #[inline]
+    pub fn active_streams(&self) -> usize {
+        self.stats.active_streams.load(Ordering::Relaxed)
+    }
+
+    /// Streams opened over the lifetime of the connection.
+    #[inline]
+    pub fn total_streams(&self) -> u64 {
+        self.stats.total_streams.load(Ordering::Relaxed)
+    }
+
+    /// The smoothed round-trip time, once a PING has been acknowledged.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.stats.rtt.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    /// Sends a PING and returns the round-trip time once it's acknowledged.
+    pub async fn ping(&self) -> anyhow::Result<Duration> {
+        self.send_ping(None).await
+    }
+
+    /// Sends a PING with `payload` and returns the round-trip time once the ACK with the same
+    /// payload arrives. Unlike [`ping`](Self::ping) it doesn't update the RTT estimate.
+    pub async fn ping_with(&self, payload: [u8; 8]) -> anyhow::Result<Duration> {
+        self.send_ping(Some(payload)).await
+    }
+
+    async fn send_ping(&self, payload: Option<[u8; 8]>) -> anyhow::Result<Duration> {
+        let (rtt_tx, rtt_rx) = oneshot::channel();
+        self.requests
+            .send(Message::Ping { payload, rtt_tx })
+            .await
+            .map_err(|_| RequestError::ConnectionClosed)?;
+        Ok(rtt_rx.await.map_err(|_| RequestError::ConnectionClosed)?)
+    }
+
+    /// Writes `data` on the connection as is, after whatever has already been queued.
+    #[cfg(feature = "unstable")]
+    pub async fn send_raw(&self, data: Bytes) -> anyhow::Result<()> {
+        self.requests
+            .send(Message::Raw { data })
+            .await
+            .map_err(|_| RequestError::ConnectionClosed)?;
+        Ok(())
+    }
+
+    /// Sends a GOAWAY, stops accepting new requests and closes the connection once
+    /// the streams in flight have completed, or `timeout` has passed.
+    pub async fn shutdown(&self, timeout: Duration) -> anyhow::Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.requests
+            .send(Message::Shutdown { timeout, done_tx })
+            .await?;
+        Ok(done_rx.await?)
+    }
+
+    /// Returns once the response headers have been received, the body is empty and
+    /// can be read from the [`BodyReader`] instead.
+    pub async fn request_streaming(
+        &self,
+        request: Request,
+    ) -> anyhow::Result<(Response, BodyReader)> {
+        if matches!(request.method, Method::Connect) {
+            return Err(anyhow!("CONNECT requests must be sent with tunnel()"));
+        }
+        let _in_flight = self.in_flight();
+        let cancel = self.cancel_on_drop();
+        let (response_tx, response_rx) = oneshot::channel();
+        let (stream_id_tx, _) = oneshot::channel();
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        self.requests
+            .send(Message::Tunnel {
+                request,
+                response_tx,
+                stream_id_tx,
+                data_tx,
+            })
+            .await
+            .map_err(|_| RequestError::ConnectionClosed)?;
+        let response = response_rx.await;
+        cancel.disarm();
+        let response = response.map_err(|_| RequestError::ConnectionClosed)??;
+        Ok((response, BodyReader::new(data_rx)))
+    }
+
+    /// Sends a CONNECT request and returns the established tunnel once the server accepts it.
+    pub async fn tunnel(&self, request: Request) -> anyhow::Result<Tunnel> {
+        let _in_flight = self.in_flight();
+        let (response_tx, response_rx) = oneshot::channel();
+        let (stream_id_tx, stream_id_rx) = oneshot::channel();
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        self.requests
+            .send(Message::Tunnel {
+                request,
+                response_tx,
+                stream_id_tx,
+                data_tx,
+            })
+            .await?;
+        // the response first, so that errors writing the request come through
+        let response = response_rx.await??;
+        let stream_id = stream_id_rx.await?;
+        if !response.ok() {
+            return Err(anyhow!("tunnel refused with status {}", response.status()));
+        }
+        Ok(Tunnel::new(
+            stream_id,
+            response,
+            data_rx,
+            self.requests.clone(),
+        ))
+    }
+}
+
+struct InFlight(Arc<StatsCounters>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wakes the connection task to reset the streams whose response nobody waits for anymore
+/// with RST_STREAM CANCEL, so the server stops sending and the buffers are freed. A request
+/// still in the channel isn't sent at all. Best effort: if the channel is full, the stream
+/// is only reset on a later cancellation.
+struct CancelOnDrop(Option<mpsc::Sender<Message>>);
+
+impl CancelOnDrop {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(requests) = self.0.take() {
+            requests.try_send(Message::Cancelled).ok();
+        }
     }
 }
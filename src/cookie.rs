@@ -0,0 +1,86 @@
+/// https://httpwg.org/specs/rfc6265.html#sane-set-cookie-syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie from a `set-cookie` response header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    /// Lowercase, without a leading dot.
+    pub domain: Option<String>,
+    /// In seconds, zero or negative means the cookie has expired.
+    pub max_age: Option<i64>,
+    /// The unparsed date.
+    pub expires: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// https://httpwg.org/specs/rfc6265.html#set-cookie
+    ///
+    /// Returns None if the header value has no `name=value` pair.
+    /// Unknown and invalid attributes are ignored, like user agents do.
+    #[must_use]
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        let mut cookie = Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        };
+        for attribute in parts {
+            let (key, value) = attribute
+                .split_once('=')
+                .map_or((attribute, ""), |(key, value)| (key, value.trim()));
+            match key.trim().to_ascii_lowercase().as_str() {
+                "path" if value.starts_with('/') => cookie.path = Some(value.to_owned()),
+                "domain" if !value.is_empty() => {
+                    cookie.domain = Some(value.trim_start_matches('.').to_ascii_lowercase());
+                }
+                "max-age" => {
+                    if let Ok(max_age) = value.parse() {
+                        cookie.max_age = Some(max_age);
+                    }
+                }
+                "expires" if !value.is_empty() => cookie.expires = Some(value.to_owned()),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => {
+                    cookie.same_site = match value.to_ascii_lowercase().as_str() {
+                        "strict" => Some(SameSite::Strict),
+                        "lax" => Some(SameSite::Lax),
+                        "none" => Some(SameSite::None),
+                        _ => None,
+                    };
+                }
+                _ => {}
+            }
+        }
+        Some(cookie)
+    }
+}
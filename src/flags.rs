@@ -76,3 +76,18 @@ pub enum Flags {
     Continuation(ContinuationFlags),
     None,
 }
+
+impl Flags {
+    /// as on the wire
+    pub fn bits(self) -> u8 {
+        match self {
+            Self::Data(flags) => flags.bits(),
+            Self::Headers(flags) => flags.bits(),
+            Self::Settings(flags) => flags.bits(),
+            Self::PushPromise(flags) => flags.bits(),
+            Self::Ping(flags) => flags.bits(),
+            Self::Continuation(flags) => flags.bits(),
+            Self::None => 0,
+        }
+    }
+}
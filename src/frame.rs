@@ -1,13 +1,39 @@
-use crate::{flags::*, stream::*, types::*};
+use crate::{connection::ConnectionState, flags::*, observer::Direction, stream::*, types::*};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use log::trace;
+use log::{trace, warn};
 use num_traits::FromPrimitive;
-use std::num::NonZeroU32;
+use std::{num::NonZeroU32, sync::atomic::Ordering};
 
-#[inline]
-fn remove_padding(data: &mut Bytes) -> Bytes {
+/// https://httpwg.org/specs/rfc7540.html#padding
+fn remove_padding(ty: FrameType, data: &mut Bytes) -> Result<Bytes, FrameDecodeError> {
+    if data.is_empty() {
+        return Err(FrameDecodeError::InvalidLength(ty, 0));
+    }
     let size = u8::from_be(data.get_u8()) as usize;
-    data.copy_to_bytes(data.len() - size - 1)
+    if size > data.len() {
+        return Err(FrameDecodeError::InvalidPadding);
+    }
+    Ok(data.copy_to_bytes(data.len() - size))
+}
+
+/// Fails unless the payload of a frame of type `ty` is exactly `length` octets.
+#[inline]
+fn check_length(ty: FrameType, payload: &Bytes, length: usize) -> Result<(), FrameDecodeError> {
+    if payload.len() == length {
+        Ok(())
+    } else {
+        Err(FrameDecodeError::InvalidLength(ty, payload.len()))
+    }
+}
+
+/// Fails if the payload of a frame of type `ty` is shorter than `length` octets.
+#[inline]
+fn check_min_length(ty: FrameType, payload: &Bytes, length: usize) -> Result<(), FrameDecodeError> {
+    if payload.len() >= length {
+        Ok(())
+    } else {
+        Err(FrameDecodeError::InvalidLength(ty, payload.len()))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -24,25 +50,14 @@ impl FrameHeader {
     pub fn write_into(self, buffer: &mut impl BufMut) {
         buffer.put(&(self.length as u32).to_be_bytes()[1..]);
         buffer.put_u8((self.ty as u8).to_be());
-        buffer.put_u8(
-            match self.flags {
-                Flags::Data(flags) => flags.bits(),
-                Flags::Headers(flags) => flags.bits(),
-                Flags::Settings(flags) => flags.bits(),
-                Flags::PushPromise(flags) => flags.bits(),
-                Flags::Ping(flags) => flags.bits(),
-                Flags::Continuation(flags) => flags.bits(),
-                Flags::None => 0_u8,
-            }
-            .to_be(),
-        );
+        buffer.put_u8(self.flags.bits().to_be());
         buffer.put(&self.stream_id.to_be_bytes()[..]);
     }
 }
 
 impl TryFrom<&mut BytesMut> for FrameHeader {
-    type Error = DecodeError;
-    fn try_from(buffer: &mut BytesMut) -> Result<FrameHeader, DecodeError> {
+    type Error = FrameDecodeError;
+    fn try_from(buffer: &mut BytesMut) -> Result<FrameHeader, FrameDecodeError> {
         if buffer.remaining() >= Self::SIZE {
             let length = u32::from_be_bytes(
                 [&[0_u8], buffer.copy_to_bytes(3).as_ref()]
@@ -50,7 +65,7 @@ impl TryFrom<&mut BytesMut> for FrameHeader {
                     .try_into()
                     .unwrap(),
             ) as usize;
-            let ty = FrameType::from_u8(buffer.get_u8()).ok_or(DecodeError::UnknownType)?;
+            let ty = FrameType::from_u8(buffer.get_u8()).unwrap_or(FrameType::Unknown);
             let flags = buffer.get_u8();
             let stream_id =
                 u32::from_be_bytes(buffer.copy_to_bytes(4).as_ref().try_into().unwrap())
@@ -72,7 +87,7 @@ impl TryFrom<&mut BytesMut> for FrameHeader {
             trace!("[RECV] {:#?}", header);
             Ok(header)
         } else {
-            Err(DecodeError::TooShort)
+            Err(FrameDecodeError::TooShort)
         }
     }
 }
@@ -117,30 +132,40 @@ pub enum FramePayload {
     WindowUpdate { increment: NonZeroU32 },
     /// https://httpwg.org/specs/rfc7540.html#CONTINUATION
     Continuation { fragment: Bytes },
+    /// https://www.rfc-editor.org/rfc/rfc7838.html#section-4
+    AltSvc { origin: String, value: String },
+    /// https://www.rfc-editor.org/rfc/rfc8336.html#section-2
+    Origin { origins: Vec<String> },
+    /// A frame of a type we don't know, its payload is discarded.
+    Unknown,
 }
 
 impl FramePayload {
-    pub fn try_from(buffer: &mut impl Buf, header: &FrameHeader) -> Result<Self, DecodeError> {
+    pub fn try_from(buffer: &mut impl Buf, header: &FrameHeader) -> Result<Self, FrameDecodeError> {
         if buffer.remaining() < header.length {
-            return Err(DecodeError::TooShort);
+            return Err(FrameDecodeError::TooShort);
         }
         let mut payload = buffer.copy_to_bytes(header.length);
+        let ty = header.ty;
 
-        let frame = match (header.ty, header.flags) {
+        // fixed-size fields are checked before they're read, a short frame is a FRAME_SIZE_ERROR
+        // https://httpwg.org/specs/rfc7540.html#FrameSize
+        let frame = match (ty, header.flags) {
             (FrameType::Data, Flags::Data(flags)) => Self::Data {
                 data: if flags.contains(DataFlags::PADDED) {
-                    remove_padding(&mut payload)
+                    remove_padding(ty, &mut payload)?
                 } else {
                     payload
                 },
             },
             (FrameType::Headers, Flags::Headers(flags)) => {
                 let mut payload = if flags.contains(HeadersFlags::PADDED) {
-                    remove_padding(&mut payload)
+                    remove_padding(ty, &mut payload)?
                 } else {
                     payload
                 };
                 if flags.contains(HeadersFlags::PRIORITY) {
+                    check_min_length(ty, &payload, 5)?;
                     let dependency = payload.get_u32();
                     Self::Headers {
                         dependency: Some(dependency & (u32::MAX >> 1)),
@@ -158,6 +183,7 @@ impl FramePayload {
                 }
             }
             (FrameType::Priority, Flags::None) => {
+                check_length(ty, &payload, 5)?;
                 let dependency = payload.get_u32();
                 Self::Priority {
                     dependency: dependency & (u32::MAX >> 1),
@@ -165,11 +191,22 @@ impl FramePayload {
                     weight: payload.get_u8(),
                 }
             }
-            (FrameType::ResetStream, Flags::None) => Self::ResetStream {
-                error: ErrorType::from_u32(payload.get_u32())
-                    .ok_or(DecodeError::UnknownErrorType)?,
-            },
-            (FrameType::Settings, Flags::Settings(_)) => {
+            (FrameType::ResetStream, Flags::None) => {
+                check_length(ty, &payload, 4)?;
+                // an unknown code gets no special treatment
+                // https://httpwg.org/specs/rfc9113.html#ErrorCodes
+                Self::ResetStream {
+                    error: ErrorType::from_u32(payload.get_u32())
+                        .unwrap_or(ErrorType::InternalError),
+                }
+            }
+            (FrameType::Settings, Flags::Settings(flags)) => {
+                // an ACK is empty, otherwise it's 6 octets per parameter
+                if !payload.len().is_multiple_of(6)
+                    || flags.contains(SettingsFlags::ACK) && !payload.is_empty()
+                {
+                    return Err(FrameDecodeError::InvalidLength(ty, payload.len()));
+                }
                 let mut params = Vec::new();
                 while payload.has_remaining() {
                     let param = payload.get_u16();
@@ -181,29 +218,80 @@ impl FramePayload {
                 }
                 Self::Settings { params }
             }
-            (FrameType::PushPromise, Flags::PushPromise(flags)) => Self::PushPromise {
-                promised_stream: NonZeroStreamId::new(payload.get_u32() & (u32::MAX >> 1))
-                    .ok_or(DecodeError::ZeroStreamId)?,
-                fragment: if flags.contains(PushPromiseFlags::PADDED) {
-                    remove_padding(&mut payload)
+            (FrameType::PushPromise, Flags::PushPromise(flags)) => {
+                // the pad length comes before the promised stream ID
+                let mut payload = if flags.contains(PushPromiseFlags::PADDED) {
+                    remove_padding(ty, &mut payload)?
                 } else {
                     payload
-                },
-            },
-            (FrameType::Ping, Flags::Ping(_)) => Self::Ping { data: payload },
-            (FrameType::GoAway, Flags::None) => Self::GoAway {
-                last_stream: payload.get_u32() & (u32::MAX >> 1),
-                error: ErrorType::from_u32(payload.get_u32())
-                    .ok_or(DecodeError::UnknownErrorType)?,
-                debug: payload,
-            },
-            (FrameType::WindowUpdate, Flags::None) => Self::WindowUpdate {
-                increment: NonZeroU32::new(payload.get_u32() & (u32::MAX >> 1))
-                    .ok_or(DecodeError::ZeroWindowIncrement)?,
-            },
+                };
+                check_min_length(ty, &payload, 4)?;
+                Self::PushPromise {
+                    promised_stream: NonZeroStreamId::new(payload.get_u32() & (u32::MAX >> 1))
+                        .ok_or(FrameDecodeError::ZeroStreamId)?,
+                    fragment: payload,
+                }
+            }
+            (FrameType::Ping, Flags::Ping(_)) => {
+                check_length(ty, &payload, 8)?;
+                Self::Ping { data: payload }
+            }
+            (FrameType::GoAway, Flags::None) => {
+                check_min_length(ty, &payload, 8)?;
+                Self::GoAway {
+                    last_stream: payload.get_u32() & (u32::MAX >> 1),
+                    error: ErrorType::from_u32(payload.get_u32())
+                        .unwrap_or(ErrorType::InternalError),
+                    debug: payload,
+                }
+            }
+            (FrameType::WindowUpdate, Flags::None) => {
+                check_length(ty, &payload, 4)?;
+                Self::WindowUpdate {
+                    increment: NonZeroU32::new(payload.get_u32() & (u32::MAX >> 1))
+                        .ok_or(FrameDecodeError::ZeroWindowIncrement)?,
+                }
+            }
             (FrameType::Continuation, Flags::Continuation(_)) => {
                 Self::Continuation { fragment: payload }
             }
+            (FrameType::AltSvc, Flags::None) => {
+                // invalid ones are ignored rather than connection errors, an empty value is
+                // never valid
+                let length = if payload.remaining() >= 2 {
+                    payload.get_u16() as usize
+                } else {
+                    usize::MAX
+                };
+                if payload.remaining() < length {
+                    Self::AltSvc {
+                        origin: String::new(),
+                        value: String::new(),
+                    }
+                } else {
+                    Self::AltSvc {
+                        origin: String::from_utf8_lossy(&payload.split_to(length)).into_owned(),
+                        value: String::from_utf8_lossy(&payload).into_owned(),
+                    }
+                }
+            }
+            (FrameType::Origin, Flags::None) => {
+                let mut origins = Vec::new();
+                // a truncated entry isn't a connection error, the rest of the frame is ignored,
+                // and so are empty entries
+                while payload.remaining() >= 2 {
+                    let length = payload.get_u16() as usize;
+                    if payload.remaining() < length {
+                        break;
+                    }
+                    let origin = payload.split_to(length);
+                    if !origin.is_empty() {
+                        origins.push(String::from_utf8_lossy(&origin).into_owned());
+                    }
+                }
+                Self::Origin { origins }
+            }
+            (FrameType::Unknown, _) => Self::Unknown,
             _ => unreachable!("impossible FrameType/Flags combos"),
         };
         //trace!("[RECV] {:#?}", frame);
@@ -223,7 +311,7 @@ impl FramePayload {
                 (Some(dependency), Some(exclusive_dependency), Some(weight)) => {
                     let mut payload: Vec<u8> = dependency.to_be_bytes().to_vec();
                     if exclusive_dependency {
-                        payload[0] &= 0b10000_u8;
+                        payload[0] |= 0x80;
                     }
                     payload.push(weight.to_be());
                     payload.extend(fragment);
@@ -239,7 +327,7 @@ impl FramePayload {
             } => {
                 let mut payload: Vec<u8> = dependency.to_be_bytes().to_vec();
                 if exclusive_dependency {
-                    payload[0] &= 0b10000_u8;
+                    payload[0] |= 0x80;
                 }
                 payload.push(weight.to_be());
                 payload.into()
@@ -275,6 +363,21 @@ impl FramePayload {
             }
             Self::WindowUpdate { increment, .. } => increment.get().to_be_bytes().to_vec().into(),
             Self::Continuation { fragment, .. } => fragment,
+            Self::AltSvc { origin, value } => {
+                let mut payload = (origin.len() as u16).to_be_bytes().to_vec();
+                payload.extend(origin.into_bytes());
+                payload.extend(value.into_bytes());
+                payload.into()
+            }
+            Self::Origin { origins } => {
+                let mut payload = Vec::new();
+                for origin in origins {
+                    payload.extend((origin.len() as u16).to_be_bytes());
+                    payload.extend(origin.into_bytes());
+                }
+                payload.into()
+            }
+            Self::Unknown => Bytes::new(),
         }
     }
 
@@ -283,7 +386,7 @@ impl FramePayload {
         buffer: &mut impl BufMut,
         stream: Option<&mut Stream>,
         flags: impl Into<Flags>,
-    ) {
+    ) -> FrameHeader {
         let ty: FrameType = (&self).into();
         let payload = self.into_payload();
         let header = FrameHeader {
@@ -294,10 +397,36 @@ impl FramePayload {
         };
 
         trace!("[SEND] {:#?}", header);
-        header.write_into(buffer);
+        header.clone().write_into(buffer);
 
         //trace!("[SEND] {:#?}", payload);
         buffer.put(&payload[..]);
+        header
+    }
+}
+
+impl FramePayload {
+    /// Queues the frame to be sent on the connection, keeping the stream state and statistics up to date.
+    pub fn send(
+        self,
+        state: &mut ConnectionState,
+        mut stream: Option<&mut Stream>,
+        flags: impl Into<Flags>,
+    ) {
+        let ty: FrameType = (&self).into();
+        let flags = flags.into();
+        if let Some(ref mut stream) = stream {
+            if let Err(err) = stream.transition_state(false, ty, flags) {
+                warn!("Sending {:?} on stream {}: {:?}", ty, stream.id, err);
+            }
+        }
+        let dumped = state.options.frame_dump.is_some().then(|| self.clone());
+        let header = self.write_into(&mut state.write_buf, stream, flags);
+        state.stats.frames_sent[ty].fetch_add(1, Ordering::Relaxed);
+        state.observe(Direction::Sent, &header);
+        if let Some(payload) = dumped {
+            state.dump(Direction::Sent, &header, &payload);
+        }
     }
 }
 
@@ -321,6 +450,68 @@ impl From<&FramePayload> for FrameType {
             FramePayload::GoAway { .. } => Self::GoAway,
             FramePayload::WindowUpdate { .. } => Self::WindowUpdate,
             FramePayload::Continuation { .. } => Self::Continuation,
+            FramePayload::AltSvc { .. } => Self::AltSvc,
+            FramePayload::Origin { .. } => Self::Origin,
+            FramePayload::Unknown => Self::Unknown,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(payload: FramePayload, flags: Flags) -> FramePayload {
+        let mut buffer = BytesMut::new();
+        let header = payload.write_into(&mut buffer, None, flags);
+        let mut decoded = buffer.split_off(FrameHeader::SIZE).freeze();
+        FramePayload::try_from(&mut decoded, &header).unwrap()
+    }
+
+    #[test]
+    fn exclusive_priority() {
+        let payload = FramePayload::Priority {
+            dependency: 3,
+            exclusive_dependency: true,
+            weight: 200,
+        };
+        assert_eq!(payload.clone().into_payload()[..], [0x80, 0, 0, 3, 200]);
+        let FramePayload::Priority {
+            dependency,
+            exclusive_dependency,
+            weight,
+        } = round_trip(payload, Flags::None)
+        else {
+            panic!("not PRIORITY");
+        };
+        assert_eq!((dependency, exclusive_dependency, weight), (3, true, 200));
+    }
+
+    #[test]
+    fn exclusive_headers_dependency() {
+        let payload = FramePayload::Headers {
+            dependency: Some(5),
+            exclusive_dependency: Some(true),
+            weight: Some(15),
+            fragment: Bytes::from_static(b"\x82"),
+        };
+        assert_eq!(
+            payload.clone().into_payload()[..],
+            [0x80, 0, 0, 5, 15, 0x82]
+        );
+        let flags = HeadersFlags::END_HEADERS | HeadersFlags::PRIORITY;
+        let FramePayload::Headers {
+            dependency,
+            exclusive_dependency,
+            weight,
+            fragment,
+        } = round_trip(payload, flags.into())
+        else {
+            panic!("not HEADERS");
+        };
+        assert_eq!(dependency, Some(5));
+        assert_eq!(exclusive_dependency, Some(true));
+        assert_eq!(weight, Some(15));
+        assert_eq!(fragment[..], [0x82]);
+    }
+}
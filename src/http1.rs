@@ -0,0 +1,395 @@
+use crate::{
+    connection::ConnectionOptions,
+    request::{check_header, Method, Request},
+    response::{Response, Timing, Version},
+    types::{append_header, Headers, RequestError},
+};
+use anyhow::{anyhow, bail};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Sends a single request over HTTP/1.1 on a connection where the server chose it over
+/// HTTP/2 with ALPN, and reads the response. The connection is closed afterwards, it isn't
+/// pooled.
+///
+/// https://httpwg.org/specs/rfc9112.html
+pub async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: Request,
+    options: &ConnectionOptions,
+) -> anyhow::Result<Response> {
+    if matches!(request.method, Method::Connect) {
+        bail!("CONNECT isn't supported over HTTP/1.1");
+    }
+    stream.write_all(&encode_head(&request, options)?).await?;
+    stream.write_all(&request.body).await?;
+    stream.flush().await?;
+
+    let mut reader = Reader {
+        stream,
+        buf: BytesMut::with_capacity(16 << 10),
+        max_head_size: options.max_header_block_size,
+    };
+    // interim responses like 100 Continue are skipped
+    let mut headers = loop {
+        let headers = reader.head().await?;
+        if !headers[":status"][0].starts_with('1') {
+            break headers;
+        }
+    };
+
+    let status = &headers[":status"][0];
+    let body = if matches!(request.method, Method::Head) || status == "204" || status == "304" {
+        Bytes::new()
+    } else if header_has_token(&headers, "transfer-encoding", "chunked") {
+        reader.chunked(options.max_body_size).await?
+    } else if let Some(length) = headers
+        .get("content-length")
+        .and_then(|values| values.first())
+    {
+        let length: usize = length
+            .parse()
+            .map_err(|_| anyhow!("invalid content-length: {}", length))?;
+        if length > options.max_body_size {
+            return Err(RequestError::BodyTooLarge.into());
+        }
+        reader.exact(length).await?
+    } else {
+        reader.read_to_end(options.max_body_size).await?
+    };
+    headers.shift_remove("transfer-encoding");
+
+    Ok(Response {
+        headers,
+        body,
+        timing: Timing::default(),
+        tls_session: None,
+        version: Version::Http11,
+    })
+}
+
+/// The request line and headers, HTTP/2-only headers like `te` and the pseudo-headers are
+/// left out.
+fn encode_head(request: &Request, options: &ConnectionOptions) -> anyhow::Result<Vec<u8>> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nhost: {}\r\n",
+        request.method.as_ref(),
+        request.path(),
+        request.authority_with_port(options.default_port_in_authority)?
+    );
+    for (name, values) in &request.headers {
+        let name = name.to_lowercase();
+        if matches!(
+            name.as_str(),
+            "host" | "connection" | "content-length" | "te"
+        ) {
+            continue;
+        }
+        for value in values {
+            check_header(&name, value)?;
+        }
+        // HTTP/1.1 allows a single cookie field
+        // https://www.rfc-editor.org/rfc/rfc6265#section-5.4
+        if name == "cookie" {
+            head.push_str("cookie: ");
+            head.push_str(&values.join("; "));
+            head.push_str("\r\n");
+            continue;
+        }
+        for value in values {
+            head.push_str(&name);
+            head.push_str(": ");
+            head.push_str(value);
+            head.push_str("\r\n");
+        }
+    }
+    if !request.body.is_empty()
+        || matches!(request.method, Method::Post | Method::Put | Method::Patch)
+    {
+        head.push_str("content-length: ");
+        head.push_str(&request.body.len().to_string());
+        head.push_str("\r\n");
+    }
+    head.push_str("connection: close\r\n\r\n");
+    Ok(head.into_bytes())
+}
+
+/// Whether a comma-separated header contains `token`, case-insensitively.
+fn header_has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    headers.get(name).into_iter().flatten().any(|value| {
+        value
+            .split(',')
+            .any(|part| part.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+struct Reader<S> {
+    stream: S,
+    buf: BytesMut,
+    max_head_size: usize,
+}
+
+impl<S: AsyncRead + Unpin> Reader<S> {
+    /// Reads more into the buffer, returns false at the end of the stream.
+    async fn fill(&mut self) -> anyhow::Result<bool> {
+        Ok(self.stream.read_buf(&mut self.buf).await? > 0)
+    }
+
+    /// A line without its CRLF, no longer than the head limit.
+    async fn line(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buf.split_to(end);
+                self.buf.advance(2);
+                return Ok(String::from_utf8_lossy(&line).into_owned());
+            }
+            if self.buf.len() >= self.max_head_size {
+                bail!("HTTP/1.1 response line too long");
+            }
+            if !self.fill().await? {
+                return Err(RequestError::ConnectionClosed.into());
+            }
+        }
+    }
+
+    /// The status line and headers, with the status as `:status` like in HTTP/2.
+    async fn head(&mut self) -> anyhow::Result<Headers> {
+        let status_line = self.line().await?;
+        let status = status_line
+            .strip_prefix("HTTP/1.")
+            .and_then(|rest| rest.split(' ').nth(1))
+            .filter(|status| status.len() == 3 && status.bytes().all(|b| b.is_ascii_digit()))
+            .ok_or(RequestError::MalformedResponse)?;
+        let mut headers = Headers::new();
+        headers.insert(":status".to_owned(), vec![status.to_owned()]);
+        let mut size = status_line.len();
+        let mut previous: Option<String> = None;
+        loop {
+            let line = self.line().await?;
+            if line.is_empty() {
+                return Ok(headers);
+            }
+            size += line.len();
+            if size > self.max_head_size {
+                return Err(RequestError::HeaderListTooLarge.into());
+            }
+            // an obs-fold continues the previous field's value after a space
+            // https://httpwg.org/specs/rfc9112.html#line.folding
+            if line.starts_with([' ', '\t']) {
+                let value = previous
+                    .as_ref()
+                    .and_then(|name| headers.get_mut(name))
+                    .and_then(|values| values.last_mut())
+                    .ok_or(RequestError::MalformedResponse)?;
+                value.push(' ');
+                value.push_str(line.trim());
+                continue;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(RequestError::MalformedResponse)?;
+            let name = name.trim().to_lowercase();
+            append_header(&mut headers, name.clone(), value.trim().to_owned());
+            previous = Some(name);
+        }
+    }
+
+    async fn exact(&mut self, length: usize) -> anyhow::Result<Bytes> {
+        while self.buf.len() < length {
+            if !self.fill().await? {
+                return Err(RequestError::ConnectionClosed.into());
+            }
+        }
+        Ok(self.buf.split_to(length).freeze())
+    }
+
+    async fn read_to_end(&mut self, max_size: usize) -> anyhow::Result<Bytes> {
+        while self.fill().await? {
+            if self.buf.len() > max_size {
+                return Err(RequestError::BodyTooLarge.into());
+            }
+        }
+        Ok(self.buf.split().freeze())
+    }
+
+    /// https://httpwg.org/specs/rfc9112.html#chunked.encoding
+    async fn chunked(&mut self, max_size: usize) -> anyhow::Result<Bytes> {
+        let mut body = BytesMut::new();
+        loop {
+            let line = self.line().await?;
+            // chunk extensions are ignored
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size =
+                usize::from_str_radix(size, 16).map_err(|_| RequestError::MalformedResponse)?;
+            if size == 0 {
+                break;
+            }
+            if body.len() + size > max_size {
+                return Err(RequestError::BodyTooLarge.into());
+            }
+            body.extend_from_slice(&self.exact(size).await?);
+            if !self.line().await?.is_empty() {
+                return Err(RequestError::MalformedResponse.into());
+            }
+        }
+        // trailers are dropped
+        while !self.line().await?.is_empty() {}
+        Ok(body.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Url;
+    use tokio::io::duplex;
+
+    fn reader(input: &[u8]) -> Reader<&[u8]> {
+        Reader {
+            stream: input,
+            buf: BytesMut::new(),
+            max_head_size: 1024,
+        }
+    }
+
+    fn error(result: anyhow::Result<impl std::fmt::Debug>) -> RequestError {
+        result
+            .unwrap_err()
+            .downcast::<RequestError>()
+            .expect("RequestError")
+    }
+
+    #[tokio::test]
+    async fn status_line() {
+        let headers = reader(b"HTTP/1.1 200 OK\r\n\r\n").head().await.unwrap();
+        assert_eq!(headers[":status"], ["200"]);
+        // the reason phrase is optional, and HTTP/1.0 servers are fine too
+        let headers = reader(b"HTTP/1.0 404 \r\n\r\n").head().await.unwrap();
+        assert_eq!(headers[":status"], ["404"]);
+
+        for status_line in [
+            &b"HTTP/2 200 OK\r\n\r\n"[..],
+            b"HTTP/1.1 20 OK\r\n\r\n",
+            b"HTTP/1.1 2000 OK\r\n\r\n",
+            b"HTTP/1.1 OK 200\r\n\r\n",
+            b"ICY 200 OK\r\n\r\n",
+        ] {
+            assert!(matches!(
+                error(reader(status_line).head().await),
+                RequestError::MalformedResponse
+            ));
+        }
+        assert!(matches!(
+            error(reader(b"HTTP/1.1 200 OK\r\n").head().await),
+            RequestError::ConnectionClosed
+        ));
+    }
+
+    #[tokio::test]
+    async fn headers() {
+        let mut input = reader(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nset-cookie: a=1\r\n\
+              Set-Cookie:b=2\r\nx-folded: one\r\n\t two\r\n  three\r\n\r\nbody",
+        );
+        let headers = input.head().await.unwrap();
+        assert_eq!(headers["content-type"], ["text/plain"]);
+        assert_eq!(headers["set-cookie"], ["a=1", "b=2"]);
+        assert_eq!(headers["x-folded"], ["one two three"]);
+        // the body is left in the buffer
+        assert_eq!(&input.buf[..], b"body");
+
+        assert!(matches!(
+            error(
+                reader(b"HTTP/1.1 200 OK\r\n folded: first\r\n\r\n")
+                    .head()
+                    .await
+            ),
+            RequestError::MalformedResponse
+        ));
+        assert!(matches!(
+            error(reader(b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n").head().await),
+            RequestError::MalformedResponse
+        ));
+        let long = format!("HTTP/1.1 200 OK\r\nx-long: {}\r\n\r\n", "a".repeat(1020));
+        assert!(matches!(
+            error(reader(long.as_bytes()).head().await),
+            RequestError::HeaderListTooLarge
+        ));
+    }
+
+    #[tokio::test]
+    async fn chunked() {
+        let mut input =
+            reader(b"5;name=value\r\nhello\r\n6 ; ext\r\n world\r\n0\r\nx-trailer: 1\r\n\r\nnext");
+        assert_eq!(input.chunked(1024).await.unwrap(), "hello world");
+        assert_eq!(&input.buf[..], b"next");
+
+        for body in [&b"5\r\nhello0\r\n\r\n"[..], b"x\r\nhello\r\n0\r\n\r\n"] {
+            assert!(matches!(
+                error(reader(body).chunked(1024).await),
+                RequestError::MalformedResponse
+            ));
+        }
+        assert!(matches!(
+            error(reader(b"5\r\nhel").chunked(1024).await),
+            RequestError::ConnectionClosed
+        ));
+        assert!(matches!(
+            error(reader(b"5\r\nhello\r\n0\r\n\r\n").chunked(4).await),
+            RequestError::BodyTooLarge
+        ));
+    }
+
+    #[tokio::test]
+    async fn exact_and_to_end() {
+        let mut input = reader(b"hello world");
+        assert_eq!(input.exact(5).await.unwrap(), "hello");
+        assert_eq!(input.read_to_end(1024).await.unwrap(), " world");
+        assert!(matches!(
+            error(reader(b"hel").exact(5).await),
+            RequestError::ConnectionClosed
+        ));
+        assert!(matches!(
+            error(reader(b"hello world").read_to_end(4).await),
+            RequestError::BodyTooLarge
+        ));
+    }
+
+    /// The body of a response to a GET, read by `request` from a server sending `response`.
+    async fn body(response: &'static [u8]) -> anyhow::Result<Bytes> {
+        let (io, mut server) = duplex(1 << 16);
+        let url = Url::parse("http://example.com/").unwrap();
+        let server = tokio::spawn(async move {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(server.read_u8().await.unwrap());
+            }
+            assert!(head.starts_with(b"GET / HTTP/1.1\r\nhost: example.com\r\n"));
+            server.write_all(response).await.unwrap();
+        });
+        let response = request(io, Request::get(url), &ConnectionOptions::default()).await;
+        server.await.unwrap();
+        response.map(|response| response.body)
+    }
+
+    #[tokio::test]
+    async fn body_framing() {
+        // content-length wins over the connection closing
+        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello, and more";
+        assert_eq!(body(response).await.unwrap(), "hello");
+        // without either, the body ends with the connection
+        let response = b"HTTP/1.1 200 OK\r\nconnection: close\r\n\r\nhello, and more";
+        assert_eq!(body(response).await.unwrap(), "hello, and more");
+        let response = b"HTTP/1.1 200 OK\r\ntransfer-encoding: gzip, Chunked\r\n\r\n\
+                         5\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(body(response).await.unwrap(), "hello");
+        // interim responses are skipped
+        let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n";
+        assert_eq!(body(response).await.unwrap(), "");
+        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello";
+        assert!(matches!(
+            error(body(response).await),
+            RequestError::ConnectionClosed
+        ));
+    }
+}
@@ -11,18 +11,40 @@
     clippy::too_many_lines, // TODO
 )]
 
+mod body;
 mod client;
 mod connection;
+mod cookie;
 mod flags;
 mod frame;
+mod http1;
+mod multipart;
+mod observer;
+mod priority;
+mod proxy;
 mod request;
 mod response;
+mod retry;
+mod stats;
 mod stream;
 mod stream_coordinator;
+mod tcp;
+mod tls;
+mod tunnel;
 mod types;
+mod window;
 
+pub use body::BodyReader;
 pub use bytes::Bytes;
-pub use client::Client;
-pub use request::{Method, Request};
-pub use response::Response;
+pub use client::{Client, ClientBuilder};
+pub use cookie::{Cookie, SameSite};
+pub use multipart::Multipart;
+pub use observer::{Direction, FrameInfo, FrameObserver};
+pub use request::{InvalidMethod, Method, Progress, Request};
+pub use response::{Response, Timing, TlsSession, Version};
+pub use retry::RetryPolicy;
+pub use stats::Stats;
+pub use tls::{Pin, TlsBackend};
+pub use tunnel::Tunnel;
+pub use types::{AltSvc, ErrorType, FrameType, Headers, RequestError, SettingsParameter};
 pub use url::Url;
@@ -1,13 +1,89 @@
 use clap::{crate_version, App, Arg};
-use http2::{Client, Request};
+use http2::{Bytes, Client, Headers, Method, Request, Response, Timing, TlsSession};
+use std::{
+    fs::File,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
 use url::Url;
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-
-    let matches = App::new("http2")
+fn app() -> App<'static, 'static> {
+    App::new("http2")
         .version(crate_version!())
+        .arg(
+            Arg::with_name("method")
+                .short("X")
+                .long("method")
+                .takes_value(true)
+                .default_value("GET")
+                .validator(|method| {
+                    method.parse::<Method>().map_err(|err| err.to_string())?;
+                    Ok(())
+                }),
+        )
+        .arg(
+            Arg::with_name("header")
+                .short("H")
+                .long("header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|header| {
+                    if header.contains(':') {
+                        Ok(())
+                    } else {
+                        Err("expected name:value".to_owned())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("data")
+                .short("d")
+                .long("data")
+                .takes_value(true)
+                .help("Request body, or @file to read it from a file"),
+        )
+        .arg(
+            Arg::with_name("include")
+                .short("i")
+                .long("include")
+                .help("Print response headers before the body"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Print request and response headers and timings to stderr"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("Write the response body to a file instead of stdout"),
+        )
+        .arg(
+            Arg::with_name("follow")
+                .short("L")
+                .long("follow")
+                .help("Follow redirects"),
+        )
+        .arg(
+            Arg::with_name("max-redirects")
+                .long("max-redirects")
+                .takes_value(true)
+                .default_value("10")
+                .validator(|max| {
+                    max.parse::<usize>().map_err(|err| err.to_string())?;
+                    Ok(())
+                }),
+        )
+        .arg(
+            Arg::with_name("insecure")
+                .short("k")
+                .long("insecure")
+                .help("Don't verify the server's certificate"),
+        )
         .arg(
             Arg::with_name("url")
                 .required(true)
@@ -18,19 +94,141 @@ async fn main() {
                     Ok(())
                 }),
         )
-        .get_matches();
+}
+
+/// `:status` first, followed by the regular headers
+fn header_lines(response: &Response) -> Vec<String> {
+    let mut lines = vec![format!(":status: {}", response.status())];
+    lines.extend(
+        response
+            .iter()
+            .filter(|(name, _)| !name.starts_with(':'))
+            .map(|(name, value)| format!("{}: {}", name, value)),
+    );
+    lines
+}
+
+fn print_tls_session(session: TlsSession) {
+    let early_data = match (session.early_data_sent, session.early_data_accepted) {
+        (false, _) => "not sent",
+        (true, false) => "rejected",
+        (true, true) => "accepted",
+    };
+    eprintln!("* 0-RTT early data: {}", early_data);
+}
+
+fn print_timing(timing: &Timing, total: Duration) {
+    for (phase, duration) in [
+        ("dns", timing.dns),
+        ("connect", timing.connect),
+        ("tls", timing.tls),
+        ("first byte", timing.first_byte),
+    ] {
+        if let Some(duration) = duration {
+            eprintln!("* {}: {:?}", phase, duration);
+        }
+    }
+    // includes the redirects, unlike the timing on the response
+    eprintln!("* total: {:?}", total);
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let matches = app().get_matches();
 
-    // unwrap: the parameter has already been validated by clap
+    // unwrap: the parameters have already been validated by clap
+    let method: Method = matches.value_of("method").unwrap().parse().unwrap();
     let urls = matches
         .values_of("url")
         .unwrap()
         .map(|url| Url::parse(url).unwrap());
 
-    let client = Client::default();
+    let mut headers = Headers::new();
+    for header in matches.values_of("header").into_iter().flatten() {
+        let (name, value) = header.split_once(':').unwrap();
+        headers
+            .entry(name.trim().to_lowercase())
+            .or_default()
+            .push(value.trim().to_owned());
+    }
+
+    let body = match matches.value_of("data") {
+        Some(data) if data.starts_with('@') => match std::fs::read(&data[1..]) {
+            Ok(data) => Bytes::from(data),
+            Err(err) => {
+                eprintln!("{}: {}", &data[1..], err);
+                std::process::exit(1);
+            }
+        },
+        Some(data) => Bytes::copy_from_slice(data.as_bytes()),
+        None => Bytes::new(),
+    };
+
+    // without --follow, a redirect is printed like any other response
+    let max_redirects: Option<usize> = matches
+        .is_present("follow")
+        .then(|| matches.value_of("max-redirects").unwrap().parse().unwrap());
+
+    let mut output: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let verbose = matches.is_present("verbose");
+    let client = Client::builder()
+        .danger_accept_invalid_certs(matches.is_present("insecure"))
+        .build();
 
     for url in urls {
-        match client.request(Request::get(url)).await {
-            Ok(response) => println!("{}", String::from_utf8_lossy(&response.body)),
+        let request = Request::new(method.clone(), url, headers.clone(), body.clone());
+        if verbose {
+            eprintln!("> {} {}", request.method, request.url);
+            for (name, values) in &request.headers {
+                for value in values {
+                    eprintln!("> {}: {}", name, value);
+                }
+            }
+        }
+        let start = Instant::now();
+        let result = match max_redirects {
+            Some(max_redirects) => {
+                client
+                    .request_following_redirects(request, max_redirects)
+                    .await
+            }
+            None => client.request(request).await,
+        };
+        match result {
+            Ok(response) => {
+                if verbose {
+                    for line in header_lines(&response) {
+                        eprintln!("< {}", line);
+                    }
+                    if let Some(session) = response.tls_session {
+                        print_tls_session(session);
+                    }
+                    print_timing(&response.timing, start.elapsed());
+                }
+                if matches.is_present("include") {
+                    for line in header_lines(&response) {
+                        println!("{}", line);
+                    }
+                    println!();
+                }
+                // write the raw bytes, so binary bodies don't get mangled
+                if let Err(err) = output.write_all(&response.body) {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            }
             Err(err) => eprintln!("{:#?}", err),
         }
     }
@@ -0,0 +1,116 @@
+use crate::types::random_u64;
+use bytes::{BufMut, Bytes, BytesMut};
+
+#[derive(Debug, Clone)]
+struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    data: Bytes,
+}
+
+/// A `multipart/form-data` body, see [`Request::post_multipart`](crate::Request::post_multipart).
+/// https://www.rfc-editor.org/rfc/rfc7578.html
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.parts.push(Part {
+            name: name.into(),
+            filename: None,
+            content_type: None,
+            data: value.into().into(),
+        });
+        self
+    }
+
+    pub fn file(
+        mut self,
+        name: impl Into<String>,
+        filename: impl Into<String>,
+        content_type: impl Into<String>,
+        data: impl Into<Bytes>,
+    ) -> Self {
+        self.parts.push(Part {
+            name: name.into(),
+            filename: Some(filename.into()),
+            content_type: Some(content_type.into()),
+            data: data.into(),
+        });
+        self
+    }
+
+    /// Returns the `content-type` header value, with the boundary, and the body.
+    #[must_use]
+    pub fn encode(&self) -> (String, Bytes) {
+        let boundary = loop {
+            let boundary = random_boundary();
+            // the boundary can't appear in any of the parts
+            if !self
+                .parts
+                .iter()
+                .any(|part| contains(&part.data, boundary.as_bytes()))
+            {
+                break boundary;
+            }
+        };
+
+        let mut body = BytesMut::new();
+        for part in &self.parts {
+            body.put_slice(b"--");
+            body.put_slice(boundary.as_bytes());
+            body.put_slice(b"\r\ncontent-disposition: form-data; name=\"");
+            body.put_slice(escape(&part.name).as_bytes());
+            body.put_slice(b"\"");
+            if let Some(ref filename) = part.filename {
+                body.put_slice(b"; filename=\"");
+                body.put_slice(escape(filename).as_bytes());
+                body.put_slice(b"\"");
+            }
+            body.put_slice(b"\r\n");
+            if let Some(ref content_type) = part.content_type {
+                body.put_slice(b"content-type: ");
+                body.put_slice(content_type.as_bytes());
+                body.put_slice(b"\r\n");
+            }
+            body.put_slice(b"\r\n");
+            body.put_slice(&part.data);
+            body.put_slice(b"\r\n");
+        }
+        body.put_slice(b"--");
+        body.put_slice(boundary.as_bytes());
+        body.put_slice(b"--\r\n");
+
+        (
+            format!("multipart/form-data; boundary={}", boundary),
+            body.freeze(),
+        )
+    }
+}
+
+fn random_boundary() -> String {
+    format!("http2-boundary-{:016x}{:016x}", random_u64(), random_u64())
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// https://html.spec.whatwg.org/multipage/form-control-infrastructure.html#multipart-form-data
+fn escape(value: &str) -> String {
+    value
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
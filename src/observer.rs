@@ -0,0 +1,341 @@
+use crate::{
+    flags::Flags,
+    frame::{FrameHeader, FramePayload},
+    types::*,
+};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Whether a frame was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// The header of a frame, as passed to a [`FrameObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub direction: Direction,
+    pub ty: FrameType,
+    /// The flag bits as on the wire, their meaning depends on the frame type.
+    pub flags: u8,
+    pub stream_id: StreamId,
+    /// Length of the payload, not counting the 9 octet header.
+    pub length: usize,
+}
+
+impl FrameInfo {
+    pub(crate) fn new(direction: Direction, header: &FrameHeader) -> Self {
+        Self {
+            direction,
+            ty: header.ty,
+            flags: header.flags.bits(),
+            stream_id: header.stream_id,
+            length: header.length,
+        }
+    }
+}
+
+/// Called with every frame sent and received, see
+/// [`ClientBuilder::on_frame`](crate::ClientBuilder::on_frame). Frames are passed on as they're
+/// queued to be written, and as soon as their header has been read. It's called from the
+/// connection task, so it shouldn't block.
+pub type FrameObserver = Arc<dyn Fn(&FrameInfo) + Send + Sync>;
+
+/// Where [`ClientBuilder::dump_frames`](crate::ClientBuilder::dump_frames) writes to.
+pub type FrameDump = Arc<Mutex<dyn Write + Send>>;
+
+/// Writes a frame like `nghttp -v` does, with `elapsed` since the connection was opened, e.g.
+///
+/// ```text
+/// [  0.043] recv SETTINGS frame <length=6, flags=0x00, stream_id=0>
+///           (niv=1)
+///           [SETTINGS_MAX_CONCURRENT_STREAMS(0x03):100]
+/// ```
+///
+/// Header blocks are only decoded after all of their frames have been read, so unlike nghttp
+/// the header fields aren't listed.
+pub(crate) fn dump_frame(
+    out: &mut dyn Write,
+    elapsed: Duration,
+    direction: Direction,
+    header: &FrameHeader,
+    payload: &FramePayload,
+) -> io::Result<()> {
+    const INDENT: &str = "          ";
+    writeln!(
+        out,
+        "[{:3}.{:03}] {} {} frame <length={}, flags=0x{:02x}, stream_id={}>",
+        elapsed.as_secs(),
+        elapsed.subsec_millis(),
+        match direction {
+            Direction::Sent => "send",
+            Direction::Received => "recv",
+        },
+        frame_type_name(header.ty),
+        header.length,
+        header.flags.bits(),
+        header.stream_id
+    )?;
+    let flags = flag_names(header.flags);
+    if !flags.is_empty() {
+        writeln!(out, "{}; {}", INDENT, flags.join(" | "))?;
+    }
+    match payload {
+        FramePayload::Headers {
+            dependency: Some(dependency),
+            exclusive_dependency: Some(exclusive),
+            weight: Some(weight),
+            ..
+        }
+        | FramePayload::Priority {
+            dependency,
+            exclusive_dependency: exclusive,
+            weight,
+        } => writeln!(
+            out,
+            "{}(dep_stream_id={}, weight={}, exclusive={})",
+            INDENT,
+            dependency,
+            u16::from(*weight) + 1,
+            u8::from(*exclusive)
+        )?,
+        FramePayload::ResetStream { error } => {
+            writeln!(out, "{}(error_code={})", INDENT, error_code(*error))?;
+        }
+        FramePayload::Settings { params } => {
+            writeln!(out, "{}(niv={})", INDENT, params.len())?;
+            for (param, value) in params {
+                writeln!(
+                    out,
+                    "{}[{}(0x{:02x}):{}]",
+                    INDENT,
+                    settings_name(*param),
+                    *param as u16,
+                    value
+                )?;
+            }
+        }
+        FramePayload::PushPromise {
+            promised_stream, ..
+        } => writeln!(out, "{}(promised_stream_id={})", INDENT, promised_stream)?,
+        FramePayload::Ping { data } => {
+            writeln!(out, "{}(opaque_data={})", INDENT, hex(data))?;
+        }
+        FramePayload::GoAway {
+            last_stream,
+            error,
+            debug,
+        } => writeln!(
+            out,
+            "{}(last_stream_id={}, error_code={}, opaque_data({})=[{}])",
+            INDENT,
+            last_stream,
+            error_code(*error),
+            debug.len(),
+            String::from_utf8_lossy(debug)
+        )?,
+        FramePayload::WindowUpdate { increment } => {
+            writeln!(out, "{}(window_size_increment={})", INDENT, increment)?;
+        }
+        FramePayload::AltSvc { origin, value } => {
+            writeln!(out, "{}(origin={}, value={})", INDENT, origin, value)?;
+        }
+        FramePayload::Origin { origins } => {
+            for origin in origins {
+                writeln!(out, "{}[{}]", INDENT, origin)?;
+            }
+        }
+        FramePayload::Data { .. }
+        | FramePayload::Headers { .. }
+        | FramePayload::Continuation { .. }
+        | FramePayload::Unknown => {}
+    }
+    out.flush()
+}
+
+fn frame_type_name(ty: FrameType) -> &'static str {
+    match ty {
+        FrameType::Data => "DATA",
+        FrameType::Headers => "HEADERS",
+        FrameType::Priority => "PRIORITY",
+        FrameType::ResetStream => "RST_STREAM",
+        FrameType::Settings => "SETTINGS",
+        FrameType::PushPromise => "PUSH_PROMISE",
+        FrameType::Ping => "PING",
+        FrameType::GoAway => "GOAWAY",
+        FrameType::WindowUpdate => "WINDOW_UPDATE",
+        FrameType::Continuation => "CONTINUATION",
+        FrameType::AltSvc => "ALTSVC",
+        FrameType::Origin => "ORIGIN",
+        FrameType::Unknown => "UNKNOWN",
+    }
+}
+
+fn flag_names(flags: Flags) -> Vec<&'static str> {
+    let known: &[(u8, &str)] = match flags {
+        Flags::Data(_) => &[(0x1, "END_STREAM"), (0x8, "PADDED")],
+        Flags::Headers(_) => &[
+            (0x1, "END_STREAM"),
+            (0x4, "END_HEADERS"),
+            (0x8, "PADDED"),
+            (0x20, "PRIORITY"),
+        ],
+        Flags::Settings(_) | Flags::Ping(_) => &[(0x1, "ACK")],
+        Flags::PushPromise(_) => &[(0x4, "END_HEADERS"), (0x8, "PADDED")],
+        Flags::Continuation(_) => &[(0x4, "END_HEADERS")],
+        Flags::None => &[],
+    };
+    let bits = flags.bits();
+    known
+        .iter()
+        .filter(|(bit, _)| bits & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn settings_name(param: SettingsParameter) -> &'static str {
+    match param {
+        SettingsParameter::HeaderTableSize => "SETTINGS_HEADER_TABLE_SIZE",
+        SettingsParameter::EnablePush => "SETTINGS_ENABLE_PUSH",
+        SettingsParameter::MaxConcurrentStreams => "SETTINGS_MAX_CONCURRENT_STREAMS",
+        SettingsParameter::InitialWindowSize => "SETTINGS_INITIAL_WINDOW_SIZE",
+        SettingsParameter::MaxFrameSize => "SETTINGS_MAX_FRAME_SIZE",
+        SettingsParameter::MaxHeaderListSize => "SETTINGS_MAX_HEADER_LIST_SIZE",
+        SettingsParameter::EnableConnectProtocol => "SETTINGS_ENABLE_CONNECT_PROTOCOL",
+    }
+}
+
+/// e.g. `PROTOCOL_ERROR(0x01)`
+fn error_code(error: ErrorType) -> String {
+    let name = match error {
+        ErrorType::NoError => "NO_ERROR",
+        ErrorType::ProtocolError => "PROTOCOL_ERROR",
+        ErrorType::InternalError => "INTERNAL_ERROR",
+        ErrorType::FlowControlError => "FLOW_CONTROL_ERROR",
+        ErrorType::SettingsTimeout => "SETTINGS_TIMEOUT",
+        ErrorType::StreamClosed => "STREAM_CLOSED",
+        ErrorType::FrameSizeError => "FRAME_SIZE_ERROR",
+        ErrorType::RefusedStream => "REFUSED_STREAM",
+        ErrorType::Cancel => "CANCEL",
+        ErrorType::CompressionError => "COMPRESSION_ERROR",
+        ErrorType::ConnectError => "CONNECT_ERROR",
+        ErrorType::EnhanceYourCalm => "ENHANCE_YOUR_CALM",
+        ErrorType::InadequateSecurity => "INADEQUATE_SECURITY",
+        ErrorType::Http11Required => "HTTP_1_1_REQUIRED",
+    };
+    format!("{}(0x{:02x})", name, error as u32)
+}
+
+fn hex(data: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut hex = String::with_capacity(data.len() * 2);
+    for byte in data {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::{HeadersFlags, SettingsFlags};
+    use bytes::Bytes;
+
+    fn dump(direction: Direction, header: &FrameHeader, payload: &FramePayload) -> String {
+        let mut out = Vec::new();
+        dump_frame(
+            &mut out,
+            Duration::from_millis(1234),
+            direction,
+            header,
+            payload,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn headers() {
+        let header = FrameHeader {
+            length: 17,
+            ty: FrameType::Headers,
+            flags: (HeadersFlags::END_STREAM | HeadersFlags::END_HEADERS | HeadersFlags::PRIORITY)
+                .into(),
+            stream_id: 13,
+        };
+        let payload = FramePayload::Headers {
+            dependency: Some(11),
+            exclusive_dependency: Some(true),
+            weight: Some(15),
+            fragment: Bytes::from_static(b"\x82\x87\x84"),
+        };
+        assert_eq!(
+            dump(Direction::Sent, &header, &payload),
+            "[  1.234] send HEADERS frame <length=17, flags=0x25, stream_id=13>\n\
+             \x20         ; END_STREAM | END_HEADERS | PRIORITY\n\
+             \x20         (dep_stream_id=11, weight=16, exclusive=1)\n"
+        );
+    }
+
+    #[test]
+    fn settings() {
+        let header = FrameHeader {
+            length: 12,
+            ty: FrameType::Settings,
+            flags: SettingsFlags::empty().into(),
+            stream_id: 0,
+        };
+        let payload = FramePayload::Settings {
+            params: vec![
+                (SettingsParameter::MaxConcurrentStreams, 100),
+                (SettingsParameter::InitialWindowSize, 65535),
+            ],
+        };
+        assert_eq!(
+            dump(Direction::Received, &header, &payload),
+            "[  1.234] recv SETTINGS frame <length=12, flags=0x00, stream_id=0>\n\
+             \x20         (niv=2)\n\
+             \x20         [SETTINGS_MAX_CONCURRENT_STREAMS(0x03):100]\n\
+             \x20         [SETTINGS_INITIAL_WINDOW_SIZE(0x04):65535]\n"
+        );
+
+        let header = FrameHeader {
+            length: 0,
+            ty: FrameType::Settings,
+            flags: SettingsFlags::ACK.into(),
+            stream_id: 0,
+        };
+        let payload = FramePayload::Settings { params: Vec::new() };
+        assert_eq!(
+            dump(Direction::Sent, &header, &payload),
+            "[  1.234] send SETTINGS frame <length=0, flags=0x01, stream_id=0>\n\
+             \x20         ; ACK\n\
+             \x20         (niv=0)\n"
+        );
+    }
+
+    #[test]
+    fn goaway() {
+        let header = FrameHeader {
+            length: 13,
+            ty: FrameType::GoAway,
+            flags: Flags::None,
+            stream_id: 0,
+        };
+        let payload = FramePayload::GoAway {
+            last_stream: 7,
+            error: ErrorType::ProtocolError,
+            debug: Bytes::from_static(b"oops!"),
+        };
+        assert_eq!(
+            dump(Direction::Received, &header, &payload),
+            "[  1.234] recv GOAWAY frame <length=13, flags=0x00, stream_id=0>\n\
+             \x20         (last_stream_id=7, error_code=PROTOCOL_ERROR(0x01), opaque_data(5)=[oops!])\n"
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use crate::types::StreamId;
+use std::collections::HashMap;
+
+/// https://httpwg.org/specs/rfc7540.html#pri-default
+const DEFAULT_WEIGHT: u64 = 16;
+
+#[derive(Debug)]
+struct Node {
+    parent: StreamId,
+    /// 1 to 256
+    weight: u64,
+    children: Vec<StreamId>,
+    /// what the stream and its dependents have sent, scaled down by the weight, so that the
+    /// sibling with the least gets to send next
+    pass: u64,
+}
+
+/// The stream dependency tree, rooted at stream 0, which decides the order of DATA frames.
+/// https://httpwg.org/specs/rfc7540.html#StreamPriority
+#[derive(Debug)]
+pub struct PriorityTree {
+    nodes: HashMap<StreamId, Node>,
+}
+
+impl Default for PriorityTree {
+    #[must_use]
+    fn default() -> Self {
+        let root = Node {
+            parent: 0,
+            weight: DEFAULT_WEIGHT,
+            children: Vec::new(),
+            pass: 0,
+        };
+        Self {
+            nodes: HashMap::from([(0, root)]),
+        }
+    }
+}
+
+impl PriorityTree {
+    #[inline]
+    pub fn contains(&self, id: StreamId) -> bool {
+        self.nodes.contains_key(&id)
+    }
+
+    /// Adds the stream with the default priority, unless it's already in the tree.
+    pub fn insert(&mut self, id: StreamId) {
+        if !self.contains(id) {
+            self.attach(id, 0, DEFAULT_WEIGHT, Vec::new());
+        }
+    }
+
+    /// https://httpwg.org/specs/rfc7540.html#reprioritize
+    ///
+    /// With `exclusive` the stream becomes the only child of `parent`, and the other children
+    /// move under it, as in the example of the section above: A made to depend exclusively on
+    /// its descendant D first moves D up to A's parent, and then takes D's children.
+    ///
+    /// `weight` is the value on the wire, one less than the actual weight.
+    pub fn reprioritize(&mut self, id: StreamId, parent: StreamId, exclusive: bool, weight: u8) {
+        debug_assert_ne!(id, parent, "streams can't depend on themselves");
+        let weight = u64::from(weight) + 1;
+        // a parent that isn't in the tree yet, such as an idle stream, is added with the
+        // default priority https://httpwg.org/specs/rfc7540.html#pri-depend
+        self.insert(parent);
+        self.insert(id);
+
+        // when the new parent depends on the stream, it's first moved to where the stream was
+        if self.is_ancestor(id, parent) {
+            let old_parent = self.nodes[&id].parent;
+            self.detach(parent);
+            self.reattach(parent, old_parent);
+        }
+
+        self.detach(id);
+        self.nodes.get_mut(&id).expect("node").weight = weight;
+        if exclusive {
+            let children = std::mem::take(&mut self.nodes.get_mut(&parent).expect("node").children);
+            for child in children {
+                self.reattach(child, id);
+            }
+        }
+        self.reattach(id, parent);
+    }
+
+    /// Removes the stream, its dependents move to its parent and share its weight.
+    /// https://httpwg.org/specs/rfc7540.html#priority-gc
+    pub fn remove(&mut self, id: StreamId) {
+        if id == 0 || !self.contains(id) {
+            return;
+        }
+        self.detach(id);
+        let node = self.nodes.remove(&id).expect("node");
+        let total: u64 = node
+            .children
+            .iter()
+            .map(|child| self.nodes[child].weight)
+            .sum();
+        for child in node.children {
+            let child_node = self.nodes.get_mut(&child).expect("node");
+            child_node.weight = (node.weight * child_node.weight / total).clamp(1, 256);
+            self.reattach(child, node.parent);
+        }
+    }
+
+    /// Picks the stream to send next out of the ones that are `ready`. A stream goes before
+    /// its dependents, and siblings share in proportion to their weights.
+    pub fn pick(&self, ready: impl Fn(StreamId) -> bool) -> Option<StreamId> {
+        // depth first without recursion, however deep the peer made the tree
+        let mut pending = vec![0];
+        while let Some(id) = pending.pop() {
+            if id != 0 && ready(id) {
+                return Some(id);
+            }
+            let mut children: Vec<_> = self.nodes[&id]
+                .children
+                .iter()
+                .map(|&child| (self.nodes[&child].pass, child))
+                .collect();
+            // the least pass is popped first
+            children.sort_unstable_by(|a, b| b.cmp(a));
+            pending.extend(children.into_iter().map(|(_, child)| child));
+        }
+        None
+    }
+
+    /// Gives the stream the default priority, see [`reprioritize`](Self::reprioritize).
+    pub fn set_default(&mut self, id: StreamId) {
+        self.reprioritize(id, 0, false, (DEFAULT_WEIGHT - 1) as u8);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Charges the bytes sent on the stream to it and the streams it depends on.
+    pub fn sent(&mut self, mut id: StreamId, bytes: usize) {
+        while id != 0 {
+            let Some(node) = self.nodes.get_mut(&id) else {
+                return;
+            };
+            node.pass += bytes as u64 * 256 / node.weight;
+            id = node.parent;
+        }
+    }
+
+    /// whether `ancestor` is above `id` in the tree
+    fn is_ancestor(&self, ancestor: StreamId, mut id: StreamId) -> bool {
+        while id != 0 {
+            id = self.nodes[&id].parent;
+            if id == ancestor {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn attach(&mut self, id: StreamId, parent: StreamId, weight: u64, children: Vec<StreamId>) {
+        let node = Node {
+            parent,
+            weight,
+            children,
+            pass: self.min_pass(parent),
+        };
+        self.nodes.insert(id, node);
+        self.nodes
+            .get_mut(&parent)
+            .expect("parent node")
+            .children
+            .push(id);
+    }
+
+    fn detach(&mut self, id: StreamId) {
+        let parent = self.nodes[&id].parent;
+        self.nodes
+            .get_mut(&parent)
+            .expect("parent node")
+            .children
+            .retain(|&child| child != id);
+    }
+
+    fn reattach(&mut self, id: StreamId, parent: StreamId) {
+        // a newcomer doesn't get to catch up on what its new siblings have sent
+        let pass = self.min_pass(parent);
+        let node = self.nodes.get_mut(&id).expect("node");
+        node.parent = parent;
+        node.pass = node.pass.max(pass);
+        self.nodes
+            .get_mut(&parent)
+            .expect("parent node")
+            .children
+            .push(id);
+    }
+
+    fn min_pass(&self, parent: StreamId) -> u64 {
+        self.nodes[&parent]
+            .children
+            .iter()
+            .map(|child| self.nodes[child].pass)
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parent(tree: &PriorityTree, id: StreamId) -> StreamId {
+        tree.nodes[&id].parent
+    }
+
+    /// The tree of the example in https://httpwg.org/specs/rfc7540.html#reprioritize,
+    /// A to F being streams 1 to 11:
+    /// ```text
+    ///     0
+    ///     |
+    ///     A
+    ///    / \
+    ///   B   C
+    ///      / \
+    ///     D   E
+    ///     |
+    ///     F
+    /// ```
+    fn example() -> PriorityTree {
+        let mut tree = PriorityTree::default();
+        for (id, parent) in [(1, 0), (3, 1), (5, 1), (7, 5), (9, 5), (11, 7)] {
+            tree.reprioritize(id, parent, false, 15);
+        }
+        tree
+    }
+
+    fn children(tree: &PriorityTree, id: StreamId) -> Vec<StreamId> {
+        let mut children = tree.nodes[&id].children.clone();
+        children.sort_unstable();
+        children
+    }
+
+    #[test]
+    fn moved_under_own_descendant() {
+        let mut tree = example();
+        tree.reprioritize(1, 7, false, 63);
+        // D moves up to A's old parent, keeping its weight, and A keeps its dependents
+        assert_eq!(children(&tree, 0), [7]);
+        assert_eq!(tree.nodes[&7].weight, 16);
+        assert_eq!(children(&tree, 7), [1, 11]);
+        assert_eq!(tree.nodes[&1].weight, 64);
+        assert_eq!(children(&tree, 1), [3, 5]);
+        assert_eq!(children(&tree, 5), [9]);
+    }
+
+    #[test]
+    fn moved_exclusively_under_own_descendant() {
+        let mut tree = example();
+        tree.reprioritize(1, 7, true, 15);
+        // A also takes D's dependent F
+        assert_eq!(children(&tree, 0), [7]);
+        assert_eq!(children(&tree, 7), [1]);
+        assert_eq!(children(&tree, 1), [3, 5, 11]);
+        assert_eq!(parent(&tree, 11), 1);
+        assert_eq!(children(&tree, 5), [9]);
+        assert_eq!(tree.pick(|_| true), Some(7));
+        assert_eq!(tree.pick(|id| id != 7), Some(1));
+    }
+
+    #[test]
+    fn exclusive_sibling() {
+        let mut tree = example();
+        tree.reprioritize(9, 1, true, 15);
+        // E becomes A's only dependent, B and C move under it
+        assert_eq!(children(&tree, 1), [9]);
+        assert_eq!(children(&tree, 9), [3, 5]);
+        assert_eq!(children(&tree, 5), [7]);
+        assert_eq!(children(&tree, 7), [11]);
+    }
+
+    #[test]
+    fn unknown_parent_is_added_with_the_default_priority() {
+        let mut tree = PriorityTree::default();
+        tree.reprioritize(3, 5, false, 31);
+        assert_eq!(parent(&tree, 5), 0);
+        assert_eq!(tree.nodes[&5].weight, DEFAULT_WEIGHT);
+        assert_eq!(parent(&tree, 3), 5);
+        assert_eq!(tree.nodes[&3].weight, 32);
+        // the parent goes first, only then its dependents
+        assert_eq!(tree.pick(|_| true), Some(5));
+        assert_eq!(tree.pick(|id| id == 3), Some(3));
+    }
+
+    #[test]
+    fn unknown_stream_depending_exclusively_on_unknown_parent() {
+        let mut tree = PriorityTree::default();
+        tree.insert(1);
+        tree.reprioritize(7, 9, true, 0);
+        assert_eq!(parent(&tree, 9), 0);
+        assert_eq!(parent(&tree, 7), 9);
+        assert_eq!(parent(&tree, 1), 0);
+        assert_eq!(tree.nodes[&9].children, [7]);
+    }
+
+    #[test]
+    fn removed_stream_leaves_no_dangling_nodes() {
+        let mut tree = PriorityTree::default();
+        tree.reprioritize(3, 5, false, 15);
+        tree.remove(5);
+        assert_eq!(parent(&tree, 3), 0);
+        // a PRIORITY frame naming the removed stream again
+        tree.reprioritize(3, 5, true, 15);
+        assert_eq!(parent(&tree, 3), 5);
+        tree.sent(3, 100);
+        tree.remove(3);
+        tree.remove(5);
+        assert_eq!(tree.nodes.len(), 1);
+        assert_eq!(tree.pick(|_| true), None);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::request::ascii_host;
+use anyhow::{anyhow, bail};
+use bytes::BytesMut;
+use percent_encoding::percent_decode_str;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use url::Url;
+
+/// Limit for the proxy's response to CONNECT, which only needs the status line.
+const MAX_RESPONSE_SIZE: usize = 16 << 10;
+
+/// Checks the scheme before connecting to the proxy.
+pub fn check_scheme(proxy: &Url) -> anyhow::Result<()> {
+    match proxy.scheme() {
+        "http" => Ok(()),
+        #[cfg(feature = "socks")]
+        "socks5" | "socks5h" => Ok(()),
+        scheme => bail!("unsupported proxy scheme: {}", scheme),
+    }
+}
+
+/// Tunnels `tcp`, connected to the proxy, to the host and port of `url`.
+pub async fn connect(mut tcp: TcpStream, proxy: &Url, url: &Url) -> anyhow::Result<TcpStream> {
+    let host = ascii_host(url)?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("connect port"))?;
+    match proxy.scheme() {
+        "http" => {
+            http_connect(&mut tcp, proxy, &format!("{}:{}", host, port)).await?;
+            Ok(tcp)
+        }
+        #[cfg(feature = "socks")]
+        "socks5" | "socks5h" => socks5_connect(tcp, proxy, url, port).await,
+        scheme => bail!("unsupported proxy scheme: {}", scheme),
+    }
+}
+
+/// The percent-decoded username and password of the proxy URL, if either is set.
+fn credentials(proxy: &Url) -> anyhow::Result<Option<(String, String)>> {
+    if proxy.username().is_empty() && proxy.password().is_none() {
+        return Ok(None);
+    }
+    let username = percent_decode_str(proxy.username()).decode_utf8()?;
+    let password = percent_decode_str(proxy.password().unwrap_or_default()).decode_utf8()?;
+    Ok(Some((username.into_owned(), password.into_owned())))
+}
+
+/// Opens an HTTP/1.1 CONNECT tunnel to `authority` through a connection to an `http` proxy.
+/// The proxy URL's username and password, if any, are sent with basic auth.
+///
+/// https://httpwg.org/specs/rfc9110.html#CONNECT
+async fn http_connect(tcp: &mut TcpStream, proxy: &Url, authority: &str) -> anyhow::Result<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nhost: {0}\r\n", authority);
+    if let Some((username, password)) = credentials(proxy)? {
+        request.push_str("proxy-authorization: Basic ");
+        request.push_str(&base64::encode(format!("{}:{}", username, password)));
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    tcp.write_all(request.as_bytes()).await?;
+
+    // the tunnel is quiet until the TLS handshake starts, so nothing is read past the response
+    let mut response = BytesMut::with_capacity(1024);
+    let head_len = loop {
+        if let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if response.len() >= MAX_RESPONSE_SIZE {
+            bail!("proxy CONNECT response too large");
+        }
+        if tcp.read_buf(&mut response).await? == 0 {
+            bail!("proxy closed the connection during CONNECT");
+        }
+    };
+    if response.len() > head_len + 4 {
+        bail!("proxy sent data before the tunnel was used");
+    }
+
+    let head = String::from_utf8_lossy(&response[..head_len]);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("malformed proxy CONNECT response: {}", status_line))?;
+    if !(200..300).contains(&status) {
+        bail!("proxy CONNECT failed: {}", status_line);
+    }
+    Ok(())
+}
+
+/// Connects to the target through a SOCKS5 proxy, with username/password auth if the proxy URL
+/// has them. Host names are resolved by the proxy, internationalized ones are sent in their
+/// punycode form.
+///
+/// https://www.rfc-editor.org/rfc/rfc1928.html
+#[cfg(feature = "socks")]
+async fn socks5_connect(
+    tcp: TcpStream,
+    proxy: &Url,
+    url: &Url,
+    port: u16,
+) -> anyhow::Result<TcpStream> {
+    use std::net::{IpAddr, SocketAddr};
+    use tokio_socks::{tcp::Socks5Stream, TargetAddr};
+    use url::Host;
+
+    let target = match url.host() {
+        Some(Host::Domain(_)) | None => TargetAddr::Domain(ascii_host(url)?.into(), port),
+        Some(Host::Ipv4(ip)) => TargetAddr::Ip(SocketAddr::new(IpAddr::V4(ip), port)),
+        Some(Host::Ipv6(ip)) => TargetAddr::Ip(SocketAddr::new(IpAddr::V6(ip), port)),
+    };
+    let stream = if let Some((username, password)) = credentials(proxy)? {
+        Socks5Stream::connect_with_password_and_socket(tcp, target, &username, &password).await?
+    } else {
+        Socks5Stream::connect_with_socket(tcp, target).await?
+    };
+    Ok(stream.into_inner())
+}
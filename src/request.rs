@@ -1,14 +1,107 @@
 use crate::{
-    connection::ConnectionState, flags::*, frame::*, response::Response,
-    stream_coordinator::StreamCoordinator, types::*,
+    connection::ConnectionState, flags::*, frame::*, multipart::Multipart, response::Response,
+    stream::Stream, stream_coordinator::StreamCoordinator, types::*,
 };
 use bytes::Bytes;
-use maplit::hashmap;
-use std::fmt;
-use tokio::sync::oneshot;
+use derivative::Derivative;
+use indexmap::indexmap;
+use log::warn;
+use std::{borrow::Borrow, fmt, str::FromStr, sync::Arc};
+use tokio::{
+    sync::oneshot,
+    time::{Duration, Instant},
+};
 use url::Url;
 
-#[derive(Debug, Clone)]
+/// https://httpwg.org/specs/rfc7540.html#ConnectionSpecific
+const CONNECTION_SPECIFIC_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Encodes the header block, with the sensitive headers as literals that are never indexed,
+/// so that they stay out of the dynamic table where compression could reveal them.
+/// Without `indexing` none of the headers go into the dynamic table, and with `size_update`
+/// the block starts by telling the peer to drop its copy of the table, and then to resize
+/// it to the given size unless that's 0.
+///
+/// https://httpwg.org/specs/rfc7541.html#compression.based.attacks
+fn encode_header_block<'h>(
+    encoder: &mut hpack::Encoder<'static>,
+    indexing: bool,
+    size_update: Option<u32>,
+    headers: impl IntoIterator<Item = (&'h [u8], &'h [u8])>,
+    is_sensitive: impl Fn(&[u8]) -> bool,
+) -> Vec<u8> {
+    let mut block = Vec::new();
+    // https://httpwg.org/specs/rfc7541.html#encoding.context.update
+    if let Some(size) = size_update {
+        block.push(0x20);
+        if size > 0 {
+            let mut update = hpack::encoder::encode_integer(size as usize, 5);
+            update[0] |= 0x20;
+            block.extend(update);
+        }
+    }
+    let mut indexable = Vec::new();
+    for (name, value) in headers {
+        let sensitive = is_sensitive(name);
+        if sensitive || !indexing {
+            block.extend(encoder.encode(indexable.drain(..)));
+            // https://httpwg.org/specs/rfc7541.html#literal.header.never.indexed
+            // https://httpwg.org/specs/rfc7541.html#literal.header.without.indexing
+            // with a literal name, which is index 0
+            block.push(if sensitive { 0x10 } else { 0x00 });
+            for string in [name, value] {
+                block.extend(hpack::encoder::encode_integer(string.len(), 7));
+                block.extend_from_slice(string);
+            }
+        } else {
+            indexable.push((name, value));
+        }
+    }
+    block.extend(encoder.encode(indexable));
+    block
+}
+
+/// The host of the URL, as used for SNI and `:authority`. `Url` already gives an
+/// internationalized domain name of an `https` or `wss` URL in its punycode `xn--` form.
+pub(crate) fn ascii_host(url: &Url) -> Result<String, RequestError> {
+    url.host_str()
+        .map(str::to_owned)
+        .ok_or(RequestError::AuthorityCannotBeBase)
+}
+
+/// Connections always use TLS, so only `https` URLs can be requested, and `wss` ones for
+/// WebSockets over extended CONNECT.
+pub(crate) fn check_scheme(url: &Url) -> Result<(), RequestError> {
+    match url.scheme() {
+        "https" | "wss" => Ok(()),
+        _ => Err(RequestError::UnsupportedScheme),
+    }
+}
+
+/// Rejects header names that aren't tokens, and values with CR, LF or NUL, which could
+/// smuggle in another header when the request is sent over HTTP/1.1 or by an intermediary.
+///
+/// https://www.rfc-editor.org/rfc/rfc9110.html#name-field-names
+/// https://httpwg.org/specs/rfc9113.html#HttpHeaders
+pub(crate) fn check_header(name: &str, value: &str) -> Result<(), RequestError> {
+    let token = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    if name.is_empty()
+        || !name.bytes().all(token)
+        || value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0'))
+    {
+        warn!("Invalid header {:?}", name);
+        return Err(RequestError::InvalidHeader);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Method {
     Get,
     Post,
@@ -17,6 +110,7 @@ pub enum Method {
     Head,
     Patch,
     Options,
+    Connect,
     Other(String),
 }
 
@@ -30,11 +124,23 @@ impl AsRef<str> for Method {
             Self::Head => "HEAD",
             Self::Patch => "PATCH",
             Self::Options => "OPTIONS",
+            Self::Connect => "CONNECT",
             Self::Other(s) => s.as_ref(),
         }
     }
 }
 
+impl Method {
+    /// https://httpwg.org/specs/rfc7231.html#idempotent.methods
+    #[must_use]
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Self::Get | Self::Head | Self::Put | Self::Delete | Self::Options
+        )
+    }
+}
+
 impl fmt::Display for Method {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
@@ -42,13 +148,87 @@ impl fmt::Display for Method {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid method: {0:?}")]
+pub struct InvalidMethod(pub String);
+
+impl FromStr for Method {
+    type Err = InvalidMethod;
+
+    /// Known methods are matched case-insensitively, anything else is kept as-is in `Other`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // https://httpwg.org/specs/rfc7230.html#rule.token.separators
+        let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if s.is_empty() || !s.chars().all(is_tchar) {
+            return Err(InvalidMethod(s.to_owned()));
+        }
+
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "GET" => Self::Get,
+            "POST" => Self::Post,
+            "PUT" => Self::Put,
+            "DELETE" => Self::Delete,
+            "HEAD" => Self::Head,
+            "PATCH" => Self::Patch,
+            "OPTIONS" => Self::Options,
+            "CONNECT" => Self::Connect,
+            _ => Self::Other(s.to_owned()),
+        })
+    }
+}
+
+impl TryFrom<&str> for Method {
+    type Error = InvalidMethod;
+
+    #[inline]
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<&http::Method> for Method {
+    fn from(method: &http::Method) -> Self {
+        match *method {
+            http::Method::GET => Self::Get,
+            http::Method::POST => Self::Post,
+            http::Method::PUT => Self::Put,
+            http::Method::DELETE => Self::Delete,
+            http::Method::HEAD => Self::Head,
+            http::Method::PATCH => Self::Patch,
+            http::Method::OPTIONS => Self::Options,
+            http::Method::CONNECT => Self::Connect,
+            ref other => Self::Other(other.as_str().to_owned()),
+        }
+    }
+}
+
+/// Called with the bytes transferred so far, and the total if known.
+pub type Progress = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
 #[must_use]
 pub struct Request {
     pub url: Url,
     pub method: Method,
     pub headers: Headers,
     pub body: Bytes,
+    /// Overrides the `:authority` derived from `url`. For CONNECT this is the tunnel target.
+    pub authority: Option<String>,
+    /// Overrides the `:path` derived from `url`, like `*` for a server-wide OPTIONS.
+    pub path: Option<String>,
+    /// The `:protocol` of an extended CONNECT request.
+    pub protocol: Option<String>,
+    /// Lowercase names of headers never to index in HPACK, on top of the client's
+    /// `ClientBuilder::sensitive_header`s.
+    pub sensitive_headers: Vec<String>,
+    /// How long `Client::request` may take in all, see [`Request::timeout`].
+    pub timeout: Option<Duration>,
+    #[derivative(Debug = "ignore")]
+    pub download_progress: Option<Progress>,
+    #[derivative(Debug = "ignore")]
+    pub upload_progress: Option<Progress>,
 }
 
 impl Request {
@@ -58,6 +238,13 @@ impl Request {
             method,
             headers,
             body: body.into(),
+            authority: None,
+            path: None,
+            protocol: None,
+            sensitive_headers: Vec::new(),
+            timeout: None,
+            download_progress: None,
+            upload_progress: None,
         }
     }
 
@@ -76,6 +263,56 @@ impl Request {
         Self::new(Method::Delete, url, Headers::new(), Bytes::new())
     }
 
+    /// The body is sent as is, set a `content-type` with [`Request::header`].
+    #[inline]
+    pub fn post(url: Url, body: impl Into<Bytes>) -> Self {
+        Self::new(Method::Post, url, Headers::new(), body)
+    }
+
+    /// The body is sent as is, set a `content-type` with [`Request::header`].
+    #[inline]
+    pub fn put(url: Url, body: impl Into<Bytes>) -> Self {
+        Self::new(Method::Put, url, Headers::new(), body)
+    }
+
+    /// The body is sent as is, set a `content-type` with [`Request::header`].
+    #[inline]
+    pub fn patch(url: Url, body: impl Into<Bytes>) -> Self {
+        Self::new(Method::Patch, url, Headers::new(), body)
+    }
+
+    /// `OPTIONS *`, about the server as a whole rather than a resource on it, with the
+    /// asterisk-form `:path`. `url` is the server to send the request to, its path is ignored.
+    /// https://httpwg.org/specs/rfc9110.html#OPTIONS
+    pub fn options_asterisk(url: Url) -> Self {
+        let mut request = Self::new(Method::Options, url, Headers::new(), Bytes::new());
+        request.path = Some("*".to_owned());
+        request
+    }
+
+    /// https://httpwg.org/specs/rfc7540.html#CONNECT
+    ///
+    /// `url` is the server to send the request to, `target` the `host:port` to tunnel to.
+    pub fn connect(url: Url, target: impl Into<String>) -> Self {
+        let mut request = Self::new(Method::Connect, url, Headers::new(), Bytes::new());
+        request.authority = Some(target.into());
+        request
+    }
+
+    /// https://www.rfc-editor.org/rfc/rfc8441.html#section-5
+    ///
+    /// Opens a WebSocket with extended CONNECT; `url` can use either the ws(s) or http(s) scheme.
+    pub fn websocket(url: Url) -> Self {
+        let mut request = Self::new(
+            Method::Connect,
+            url,
+            indexmap! { "sec-websocket-version".to_owned() => vec!["13".to_owned()] },
+            Bytes::new(),
+        );
+        request.protocol = Some("websocket".to_owned());
+        request
+    }
+
     #[cfg(feature = "json")]
     pub fn post_json<T>(url: Url, body: &T) -> serde_json::Result<Self>
     where
@@ -84,7 +321,7 @@ impl Request {
         Ok(Self::new(
             Method::Post,
             url,
-            hashmap! { "content-type".to_owned() => vec!["application/json".to_owned()] },
+            indexmap! { "content-type".to_owned() => vec!["application/json".to_owned()] },
             serde_json::to_vec(body)?,
         ))
     }
@@ -97,7 +334,7 @@ impl Request {
         Ok(Self::new(
             Method::Put,
             url,
-            hashmap! { "content-type".to_owned() => vec!["application/json".to_owned()] },
+            indexmap! { "content-type".to_owned() => vec!["application/json".to_owned()] },
             serde_json::to_vec(body)?,
         ))
     }
@@ -110,11 +347,145 @@ impl Request {
         Ok(Self::new(
             Method::Patch,
             url,
-            hashmap! { "content-type".to_owned() => vec!["application/json".to_owned()] },
+            indexmap! { "content-type".to_owned() => vec!["application/json".to_owned()] },
             serde_json::to_vec(body)?,
         ))
     }
 
+    /// `application/x-www-form-urlencoded`, e.g. from `&[("name", "value")]`
+    #[cfg(feature = "form")]
+    pub fn post_form<T>(url: Url, body: &T) -> Result<Self, serde_urlencoded::ser::Error>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        Ok(Self::new(
+            Method::Post,
+            url,
+            indexmap! { "content-type".to_owned() => vec!["application/x-www-form-urlencoded".to_owned()] },
+            serde_urlencoded::to_string(body)?,
+        ))
+    }
+
+    /// The body is buffered in full.
+    pub fn post_multipart(url: Url, multipart: &Multipart) -> Self {
+        let (content_type, body) = multipart.encode();
+        Self::new(
+            Method::Post,
+            url,
+            indexmap! { "content-type".to_owned() => vec![content_type] },
+            body,
+        )
+    }
+
+    /// Appends URL-encoded query parameters to the URL, after any it already has.
+    pub fn query<I, K, V>(mut self, pairs: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Borrow<(K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.url.query_pairs_mut().extend_pairs(pairs);
+        self
+    }
+
+    /// Adds a value to the header, after any it already has. The name is lowercased.
+    pub fn header(mut self, name: impl AsRef<str>, value: impl Into<String>) -> Self {
+        self.append_header(name, value);
+        self
+    }
+
+    /// Replaces the values of the header, whatever the case of their names. The name is
+    /// lowercased.
+    pub fn set_header(mut self, name: impl AsRef<str>, value: impl Into<String>) -> Self {
+        self.insert_header(name, value);
+        self
+    }
+
+    /// Like [`header`](Self::header), but on a request in place.
+    pub fn append_header(&mut self, name: impl AsRef<str>, value: impl Into<String>) -> &mut Self {
+        self.headers
+            .entry(name.as_ref().to_lowercase())
+            .or_default()
+            .push(value.into());
+        self
+    }
+
+    /// Like [`set_header`](Self::set_header), but on a request in place.
+    pub fn insert_header(&mut self, name: impl AsRef<str>, value: impl Into<String>) -> &mut Self {
+        let name = name.as_ref().to_lowercase();
+        self.headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(&name));
+        self.headers.insert(name, vec![value.into()]);
+        self
+    }
+
+    /// Marks the header as sensitive for this request, so that it's never added to the HPACK
+    /// dynamic table, like `authorization` and `cookie` are by default.
+    pub fn sensitive_header(mut self, name: impl AsRef<str>) -> Self {
+        self.sensitive_headers.push(name.as_ref().to_lowercase());
+        self
+    }
+
+    /// Sets `authorization` to HTTP Basic auth with the credentials, replacing any it had.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc7617.html
+    pub fn basic_auth(
+        self,
+        username: impl fmt::Display,
+        password: Option<impl fmt::Display>,
+    ) -> Self {
+        let credentials = match password {
+            Some(password) => format!("{}:{}", username, password),
+            None => format!("{}:", username),
+        };
+        self.set_header(
+            "authorization",
+            format!("Basic {}", base64::encode(credentials)),
+        )
+    }
+
+    /// Sets `authorization` to a bearer token, replacing any it had.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc6750.html#section-2.1
+    pub fn bearer_auth(self, token: impl fmt::Display) -> Self {
+        self.set_header("authorization", format!("Bearer {}", token))
+    }
+
+    /// Sends `authority` as the `:authority` instead of the host and port of the URL, which
+    /// still decides where to connect and the TLS server name. Sending the request fails with
+    /// [`RequestError::EmptyAuthority`] if it's empty.
+    pub fn override_authority(mut self, authority: impl Into<String>) -> Self {
+        self.authority = Some(authority.into());
+        self
+    }
+
+    /// Fails [`Client::request`](crate::Client::request) with [`RequestError::Timeout`] unless
+    /// it completes within `timeout`, which covers connecting, retries and their backoff, and
+    /// receiving the whole response. Defaults to no timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Calls `callback` as the response body arrives, with the total from `content-length`.
+    pub fn on_download_progress(
+        mut self,
+        callback: impl Fn(u64, Option<u64>) + Send + Sync + 'static,
+    ) -> Self {
+        self.download_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Calls `callback` as the request body is written to the socket.
+    pub fn on_upload_progress(
+        mut self,
+        callback: impl Fn(u64, Option<u64>) + Send + Sync + 'static,
+    ) -> Self {
+        self.upload_progress = Some(Arc::new(callback));
+        self
+    }
+
     pub fn redirect(&self, response: &Response) -> Option<Self> {
         let (method, body) = match response.status() {
             // change method to GET
@@ -130,80 +501,331 @@ impl Request {
             .header("location")
             .and_then(|location| self.url.join(location).ok())?;
 
-        Some(Self::new(method, location, self.headers.clone(), body))
+        Some(Self {
+            download_progress: self.download_progress.clone(),
+            upload_progress: self.upload_progress.clone(),
+            sensitive_headers: self.sensitive_headers.clone(),
+            timeout: self.timeout,
+            ..Self::new(method, location, self.headers.clone(), body)
+        })
     }
 
+    /// returns the ID of the stream the request was written to,
+    /// errors are also passed on to `response_tx`
     pub(crate) fn write_into(
         self,
         state: &mut ConnectionState,
         streams: &mut StreamCoordinator,
-        response_tx: oneshot::Sender<Response>,
-    ) -> Result<(), RequestError> {
-        let path = if let Some(query) = self.url.query() {
+        response_tx: oneshot::Sender<Result<Response, RequestError>>,
+    ) -> Result<NonZeroStreamId, RequestError> {
+        match self.write_stream(state, streams) {
+            Ok(stream) => {
+                stream.response_tx = Some(response_tx);
+                Ok(stream.id)
+            }
+            Err(err) => {
+                response_tx.send(Err(err)).ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// The override, or the path and query of the URL, for `:path`.
+    pub(crate) fn path(&self) -> String {
+        if let Some(ref path) = self.path {
+            path.clone()
+        } else if let Some(query) = self.url.query() {
             format!("{}?{}", self.url.path(), query)
         } else {
             self.url.path().to_owned()
-        };
-        let authority = if let Some(port) = self.url.port() {
-            format!(
-                "{}:{}",
-                self.url.host().ok_or(RequestError::AuthorityCannotBeBase)?,
-                port
-            )
+        }
+    }
+
+    /// The `:authority` the request is sent with: the override if there is one, or else the
+    /// host of the URL, with the port only if it isn't the default for the scheme.
+    /// See [`ClientBuilder::default_port_in_authority`](crate::ClientBuilder::default_port_in_authority)
+    /// to always include it.
+    pub fn effective_authority(&self) -> Result<String, RequestError> {
+        self.authority_with_port(false)
+    }
+
+    /// Like `effective_authority`, but with `default_port` the port is included even when
+    /// it's the default for the scheme.
+    pub(crate) fn authority_with_port(&self, default_port: bool) -> Result<String, RequestError> {
+        match self.authority {
+            Some(ref authority) if authority.is_empty() => {
+                return Err(RequestError::EmptyAuthority)
+            }
+            Some(ref authority) => return Ok(authority.clone()),
+            None => {}
+        }
+        let port = if default_port {
+            self.url.port_or_known_default()
         } else {
-            self.url
-                .host()
-                .ok_or(RequestError::AuthorityCannotBeBase)?
-                .to_string()
+            self.url.port()
         };
-        let pseudo_headers: [(&[u8], &[u8]); 4] = [
-            (b":method", self.method.as_ref().as_bytes()),
-            (b":scheme", self.url.scheme().as_bytes()),
-            (b":path", path.as_bytes()),
-            (b":authority", authority.as_bytes()),
-        ];
-        let headers: Vec<(String, String)> = self
-            .headers
-            .into_iter()
+        match port {
+            Some(port) => Ok(format!("{}:{}", ascii_host(&self.url)?, port)),
+            None => ascii_host(&self.url),
+        }
+    }
+
+    fn write_stream<'s>(
+        self,
+        state: &mut ConnectionState,
+        streams: &'s mut StreamCoordinator,
+    ) -> Result<&'s mut Stream, RequestError> {
+        let is_connect = matches!(self.method, Method::Connect);
+        let path = self.path();
+        let authority = self.authority_with_port(state.options.default_port_in_authority)?;
+        let scheme = match self.url.scheme() {
+            "ws" => "http",
+            "wss" => "https",
+            scheme => scheme,
+        };
+        let pseudo_headers: Vec<(&[u8], &[u8])> =
+            if let (true, Some(protocol)) = (is_connect, &self.protocol) {
+                if state.their_settings[SettingsParameter::EnableConnectProtocol] != 1 {
+                    return Err(RequestError::ExtendedConnectNotSupported);
+                }
+                vec![
+                    (b":method", self.method.as_ref().as_bytes()),
+                    (b":protocol", protocol.as_bytes()),
+                    (b":scheme", scheme.as_bytes()),
+                    (b":path", path.as_bytes()),
+                    (b":authority", authority.as_bytes()),
+                ]
+            } else if is_connect {
+                // CONNECT omits :scheme and :path
+                vec![
+                    (b":method", self.method.as_ref().as_bytes()),
+                    (b":authority", authority.as_bytes()),
+                ]
+            } else {
+                vec![
+                    (b":method", self.method.as_ref().as_bytes()),
+                    (b":scheme", scheme.as_bytes()),
+                    (b":path", path.as_bytes()),
+                    (b":authority", authority.as_bytes()),
+                ]
+            };
+        // a CONNECT stream stays open in both directions for the tunnel
+        let end_stream = self.body.is_empty() && !is_connect;
+        // https://www.rfc-editor.org/rfc/rfc9110.html#name-expect
+        let expect_continue = self.headers.iter().any(|(name, values)| {
+            name.eq_ignore_ascii_case("expect")
+                && values
+                    .iter()
+                    .any(|value| value.eq_ignore_ascii_case("100-continue"))
+        });
+        let mut headers: Vec<(String, String)> = Vec::with_capacity(self.headers.len());
+        for (name, values) in self.headers {
             // header names MUST be lowercase
-            .flat_map(|(k, vs)| vs.into_iter().map(move |v| (k.to_lowercase(), v)))
-            .collect();
+            let name = name.to_lowercase();
+            if let Some(&forbidden) = CONNECTION_SPECIFIC_HEADERS.iter().find(|&&f| f == name) {
+                if state.options.reject_connection_headers {
+                    return Err(RequestError::ConnectionSpecificHeader(forbidden));
+                }
+                warn!("Dropping connection-specific header {}", name);
+                continue;
+            }
+            // https://httpwg.org/specs/rfc9113.html#ConnectionSpecific
+            let values = if name == "te" {
+                if !values
+                    .iter()
+                    .all(|value| value.eq_ignore_ascii_case("trailers"))
+                {
+                    return Err(RequestError::InvalidTe);
+                }
+                vec!["trailers".to_owned(); values.len()]
+            } else {
+                values
+            };
+            for value in &values {
+                check_header(&name, value)?;
+            }
+            headers.extend(values.into_iter().map(|value| (name.clone(), value)));
+        }
 
         let stream = streams.create_mut().ok_or(RequestError::OutOfStreamIds)?;
-        stream.response_tx = Some(response_tx);
+        stream.started = Some(Instant::now());
+        stream.is_head = matches!(self.method, Method::Head);
+        stream.download_progress = self.download_progress;
 
         FramePayload::Headers {
             dependency: None,
             exclusive_dependency: None,
             weight: None,
-            fragment: state
-                .header_encoder
-                .encode(
-                    // pseudo-headers MUST be first
-                    pseudo_headers
-                        .into_iter()
-                        .chain(headers.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes()))),
-                )
-                .into(),
+            fragment: encode_header_block(
+                &mut state.header_encoder,
+                state.encoder_indexing,
+                std::mem::take(&mut state.encoder_size_update),
+                // pseudo-headers MUST be first
+                pseudo_headers
+                    .into_iter()
+                    .chain(headers.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes()))),
+                |name| {
+                    state
+                        .options
+                        .sensitive_headers
+                        .iter()
+                        .chain(&self.sensitive_headers)
+                        .any(|sensitive| sensitive.as_bytes() == name)
+                },
+            )
+            .into(),
         }
-        .write_into(
-            &mut state.write_buf,
+        .send(
+            state,
             Some(stream),
-            if self.body.is_empty() {
+            if end_stream {
                 HeadersFlags::END_STREAM | HeadersFlags::END_HEADERS
             } else {
                 HeadersFlags::END_HEADERS
             },
         );
 
-        if !self.body.is_empty() {
-            FramePayload::Data { data: self.body }.write_into(
-                &mut state.write_buf,
-                Some(stream),
-                DataFlags::END_STREAM,
-            );
+        // the body is sent by the scheduler, interleaved with the other streams
+        stream.upload_progress = self.upload_progress;
+        let stream_id = stream.id;
+        if expect_continue && !self.body.is_empty() && !is_connect {
+            stream.withheld_body = Some(self.body);
+        } else if !self.body.is_empty() {
+            streams.queue_data(stream_id, self.body, !is_connect);
         }
 
-        Ok(())
+        Ok(streams.get_mut(stream_id))
+    }
+}
+
+#[cfg(feature = "http")]
+impl TryFrom<http::Request<Bytes>> for Request {
+    type Error = url::ParseError;
+
+    fn try_from(request: http::Request<Bytes>) -> Result<Self, Self::Error> {
+        let (parts, body) = request.into_parts();
+        let mut headers = Headers::new();
+        for (name, value) in &parts.headers {
+            // HeaderMap yields repeated names once per value
+            headers
+                .entry(name.as_str().to_owned())
+                .or_default()
+                .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+        Ok(Self::new(
+            Method::from(&parts.method),
+            Url::parse(&parts.uri.to_string())?,
+            headers,
+            body,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(
+        encoder: &mut hpack::Encoder<'static>,
+        indexing: bool,
+        size_update: Option<u32>,
+        headers: &[(&str, &str)],
+    ) -> Vec<u8> {
+        encode_header_block(
+            encoder,
+            indexing,
+            size_update,
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_bytes(), value.as_bytes())),
+            |name| name == b"authorization",
+        )
+    }
+
+    #[test]
+    fn sensitive_headers_are_never_indexed() {
+        let mut encoder = hpack::Encoder::new();
+        let headers = [(":method", "GET"), ("authorization", "secret")];
+        let block = encode(&mut encoder, true, None, &headers);
+        // :method GET is index 2 of the static table
+        assert_eq!(
+            block,
+            [&[0x82, 0x10, 13][..], b"authorization", &[6], b"secret"].concat()
+        );
+        // and it stays out of the dynamic table, so the next block spells it out again
+        assert_eq!(encode(&mut encoder, true, None, &headers), block);
+
+        let decoded = hpack::Decoder::new().decode(&block).unwrap();
+        assert_eq!(
+            decoded,
+            [
+                (b":method".to_vec(), b"GET".to_vec()),
+                (b"authorization".to_vec(), b"secret".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn literals_without_indexing() {
+        let mut encoder = hpack::Encoder::new();
+        let block = encode(&mut encoder, false, Some(0), &[("x-a", "1")]);
+        // a size update to 0, then a literal that's not indexed, but may be by intermediaries
+        assert_eq!(block, [&[0x20, 0x00, 3][..], b"x-a", &[1], b"1"].concat());
+    }
+
+    #[test]
+    fn idn_hosts_are_punycode() {
+        let host = |url| ascii_host(&Url::parse(url).unwrap());
+        assert_eq!(host("https://münchen.de/").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(host("wss://MÜNCHEN.de:8443/").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(host("https://example.com/").unwrap(), "example.com");
+        assert_eq!(host("https://[::1]/").unwrap(), "[::1]");
+        assert_eq!(
+            host("data:text/plain,hi"),
+            Err(RequestError::AuthorityCannotBeBase)
+        );
+    }
+
+    #[test]
+    fn redirect_keeps_sensitive_headers() {
+        let request = Request::get(Url::parse("https://example.com/a").unwrap())
+            .set_header("x-token", "secret")
+            .sensitive_header("x-token");
+        let response = Response {
+            headers: [
+                (":status".to_owned(), vec!["302".to_owned()]),
+                ("location".to_owned(), vec!["/b".to_owned()]),
+            ]
+            .into_iter()
+            .collect(),
+            body: Bytes::new(),
+            timing: crate::response::Timing::default(),
+            tls_session: None,
+            version: crate::response::Version::Http2,
+        };
+        let redirect = request.redirect(&response).unwrap();
+        assert_eq!(redirect.url.path(), "/b");
+        assert_eq!(redirect.headers["x-token"], ["secret"]);
+        assert_eq!(redirect.sensitive_headers, ["x-token"]);
+    }
+
+    #[test]
+    fn table_resized_after_clearing() {
+        let mut encoder = hpack::Encoder::new();
+        let block = encode(&mut encoder, true, Some(4096), &[("x-a", "1")]);
+        // 4096 is 31 on the 5-bit prefix and 4065 in two more octets
+        assert_eq!(block[..4], [0x20, 0x3f, 0xe1, 0x1f]);
+        let mut decoder = hpack::Decoder::new();
+        assert_eq!(
+            decoder.decode(&block).unwrap(),
+            [(b"x-a".to_vec(), b"1".to_vec())]
+        );
+        // indexed, so the next block refers to the entry
+        let block = encode(&mut encoder, true, None, &[("x-a", "1")]);
+        assert_eq!(block, [0xbe]);
+        assert_eq!(
+            decoder.decode(&block).unwrap(),
+            [(b"x-a".to_vec(), b"1".to_vec())]
+        );
     }
 }
@@ -1,11 +1,47 @@
-use crate::types::Headers;
+use crate::{cookie::Cookie, types::Headers};
 use bytes::Bytes;
-use std::borrow::Cow;
+use std::{borrow::Cow, str::Utf8Error, time::Duration};
+
+/// How long the phases of a request took. The connection phases are only set
+/// for the request that established the connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// From sending the request to receiving the first response HEADERS.
+    pub first_byte: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+/// How the TLS session of a connection was established. rustls doesn't report session
+/// resumption on its own, but early data can only be accepted on a resumed session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TlsSession {
+    /// The connection preface was sent as 0-RTT early data, on a session ticket from earlier.
+    pub early_data_sent: bool,
+    /// The server accepted the early data, so the session was resumed.
+    pub early_data_accepted: bool,
+}
+
+/// The HTTP version a response was received with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum Version {
+    /// Only with `ClientBuilder::http1_fallback`, when the server chose it with ALPN.
+    Http11,
+    #[default]
+    Http2,
+}
 
 #[derive(Debug, Clone)]
 pub struct Response {
     pub headers: Headers,
     pub body: Bytes,
+    pub timing: Timing,
+    /// Only set for the request that established the connection.
+    pub tls_session: Option<TlsSession>,
+    pub version: Version,
 }
 
 impl Response {
@@ -20,6 +56,21 @@ impl Response {
             .and_then(|values| values.first().map(String::as_ref))
     }
 
+    /// Every header field as a name and value, a field with several values once per value.
+    /// The pseudo-headers like `:status` are included.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(move |value| (name.as_str(), value.as_str()))
+        })
+    }
+
+    /// The names of the header fields, each once, including the pseudo-headers.
+    pub fn header_names(&self) -> impl Iterator<Item = &str> {
+        self.headers.keys().map(String::as_str)
+    }
+
     pub fn status(&self) -> u16 {
         self.header(":status")
             .expect("no status in response")
@@ -27,16 +78,69 @@ impl Response {
             .expect("non-number status")
     }
 
+    #[inline]
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The canonical reason phrase of the status, like `OK` for 200. HTTP/2 has no reason
+    /// phrases, and the one an HTTP/1.1 server sent isn't kept, so this is derived from the
+    /// status alone. `None` for unregistered statuses.
+    pub fn reason(&self) -> Option<&'static str> {
+        canonical_reason(self.status())
+    }
+
     #[inline]
     pub fn ok(&self) -> bool {
         (200..300).contains(&self.status())
     }
 
+    /// Parsed from the `set-cookie` headers, skipping any that are invalid.
+    pub fn cookies(&self) -> Vec<Cookie> {
+        self.headers("set-cookie")
+            .into_iter()
+            .flatten()
+            .filter_map(|header| Cookie::parse(header))
+            .collect()
+    }
+
+    #[inline]
+    pub fn bytes(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Invalid UTF-8 is replaced with U+FFFD, see [`Response::text_checked`] to catch it instead.
     #[inline]
     pub fn text(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.body)
     }
 
+    /// The `charset` parameter of the `content-type`, if any.
+    pub fn charset(&self) -> Option<&str> {
+        self.header("content-type")?
+            .split(';')
+            .skip(1)
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+            .map(|(_, value)| value.trim().trim_matches('"'))
+    }
+
+    /// Decodes the body with the charset of the `content-type`, defaulting to UTF-8.
+    /// Like [`Response::text`], malformed sequences are replaced with U+FFFD.
+    #[cfg(feature = "encoding")]
+    pub fn text_with_charset(&self) -> Cow<'_, str> {
+        let encoding = self
+            .charset()
+            .and_then(|charset| encoding_rs::Encoding::for_label(charset.as_bytes()))
+            .unwrap_or(encoding_rs::UTF_8);
+        encoding.decode(&self.body).0
+    }
+
+    #[inline]
+    pub fn text_checked(&self) -> Result<String, Utf8Error> {
+        std::str::from_utf8(&self.body).map(str::to_owned)
+    }
+
     #[cfg(feature = "json")]
     #[inline]
     pub fn json<'a, T>(&'a self) -> serde_json::Result<T>
@@ -46,3 +150,95 @@ impl Response {
         serde_json::from_slice(&self.body)
     }
 }
+
+#[cfg(feature = "http")]
+impl TryFrom<Response> for http::Response<Bytes> {
+    type Error = http::Error;
+
+    fn try_from(response: Response) -> Result<Self, Self::Error> {
+        let mut builder = http::Response::builder()
+            // a missing or invalid one is an error rather than a panic
+            .status(response.header(":status").unwrap_or_default())
+            .version(match response.version {
+                Version::Http11 => http::Version::HTTP_11,
+                Version::Http2 => http::Version::HTTP_2,
+            });
+        for (name, values) in &response.headers {
+            // pseudo-headers are represented by the status and version instead
+            if name.starts_with(':') {
+                continue;
+            }
+            for value in values {
+                builder = builder.header(name.as_str(), value.as_str());
+            }
+        }
+        builder.body(response.body)
+    }
+}
+
+/// https://www.iana.org/assignments/http-status-codes/http-status-codes.xhtml
+fn canonical_reason(status: u16) -> Option<&'static str> {
+    Some(match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        102 => "Processing",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        208 => "Already Reported",
+        226 => "IM Used",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        305 => "Use Proxy",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        506 => "Variant Also Negotiates",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        511 => "Network Authentication Required",
+        _ => return None,
+    })
+}
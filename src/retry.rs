@@ -0,0 +1,48 @@
+use crate::types::random_u64;
+use std::time::Duration;
+
+/// How failed requests are retried, see [`ClientBuilder::retry_policy`](crate::ClientBuilder::retry_policy).
+///
+/// Retries wait with exponential backoff: the `n`th waits for about `initial_backoff * 2^n`,
+/// up to `max_backoff`, with a random half of it as jitter so that clients that failed
+/// together don't retry together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after a connection failure, see [`ClientBuilder::retries`](crate::ClientBuilder::retries).
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// No retry is started once this long has passed since the first attempt.
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_duration: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before the retry, which is the `retry`th starting from 0, or `None`
+    /// if it would start after `max_duration` since the first attempt, `elapsed` ago.
+    #[must_use]
+    pub fn backoff(&self, retry: usize, elapsed: Duration) -> Option<Duration> {
+        let exponential = u32::try_from(retry)
+            .ok()
+            .and_then(|retry| 2_u32.checked_pow(retry))
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        let random = random_u64();
+        let backoff = exponential / 2 + exponential / 2 / 1000 * (random % 1000) as u32;
+        match self.max_duration {
+            Some(max) if elapsed + backoff > max => None,
+            _ => Some(backoff),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::types::{AltSvc, FrameType, SettingsParameter};
+use enum_map::{enum_map, EnumMap};
+use std::{
+    ops::AddAssign,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Shared between a `Connection` and its task, which does all the updating.
+#[derive(Debug, Default)]
+pub struct StatsCounters {
+    pub frames_sent: EnumMap<FrameType, AtomicU64>,
+    pub frames_received: EnumMap<FrameType, AtomicU64>,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub active_streams: AtomicUsize,
+    pub total_streams: AtomicU64,
+    pub send_window: AtomicUsize,
+    /// SETTINGS_MAX_CONCURRENT_STREAMS of the peer
+    pub max_concurrent_streams: AtomicU32,
+    /// requests sent to the task that haven't got a response yet
+    pub in_flight: AtomicUsize,
+    /// smoothed round-trip time from PINGs in nanoseconds, 0 until the first is acknowledged
+    pub rtt: AtomicU64,
+    /// set once the peer has sent a GOAWAY
+    pub going_away: AtomicBool,
+    /// the origin set from ORIGIN frames, empty if the peer hasn't sent any
+    pub origins: Mutex<Vec<String>>,
+    /// the latest ALTSVC for each origin
+    pub alt_svc: Mutex<Vec<AltSvc>>,
+    /// settings of the peer, replaced as a whole after each SETTINGS frame
+    pub peer_settings: Mutex<EnumMap<SettingsParameter, u32>>,
+}
+
+impl StatsCounters {
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            frames_sent: enum_map! { ty => self.frames_sent[ty].load(Ordering::Relaxed) },
+            frames_received: enum_map! { ty => self.frames_received[ty].load(Ordering::Relaxed) },
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            active_streams: self.active_streams.load(Ordering::Relaxed),
+            total_streams: self.total_streams.load(Ordering::Relaxed),
+            send_window: self.send_window.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the counters of a connection, or the sum over several.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub frames_sent: EnumMap<FrameType, u64>,
+    pub frames_received: EnumMap<FrameType, u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Streams that haven't been closed yet.
+    pub active_streams: usize,
+    /// Streams opened over the lifetime of the connection.
+    pub total_streams: u64,
+    /// The remaining connection-level flow-control window for sending.
+    pub send_window: usize,
+}
+
+impl AddAssign<&Stats> for Stats {
+    fn add_assign(&mut self, other: &Stats) {
+        for (ty, count) in &other.frames_sent {
+            self.frames_sent[ty] += count;
+        }
+        for (ty, count) in &other.frames_received {
+            self.frames_received[ty] += count;
+        }
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.active_streams += other.active_streams;
+        self.total_streams += other.total_streams;
+        self.send_window += other.send_window;
+    }
+}
@@ -1,10 +1,20 @@
-use crate::{connection::*, flags::*, frame::*, response::Response, types::*};
-use anyhow::anyhow;
-use bytes::BytesMut;
+use crate::{
+    connection::*,
+    flags::*,
+    frame::*,
+    request::Progress,
+    response::{Response, Timing, Version},
+    types::*,
+};
+use bytes::{Bytes, BytesMut};
 use derivative::Derivative;
-use log::{trace, warn};
-use std::num::NonZeroU32;
-use tokio::sync::oneshot;
+use hpack::decoder::{DecoderError, IntegerDecodingError};
+use log::{debug, trace, warn};
+use std::{collections::VecDeque, num::NonZeroU32, sync::Arc};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{Duration, Instant},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 enum StreamState {
@@ -25,11 +35,44 @@ enum Continuing {
 
 #[derive(Derivative)]
 #[derivative(Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Stream {
     pub id: NonZeroStreamId,
-    pub response_tx: Option<oneshot::Sender<Response>>,
-    window_remaining: u64,
+    pub response_tx: Option<oneshot::Sender<Result<Response, RequestError>>>,
+    /// set for CONNECT and streamed responses, receives the DATA instead of the response body
+    pub data_tx: Option<mpsc::UnboundedSender<Result<Bytes, RequestError>>>,
+    /// when the request was written
+    pub started: Option<Instant>,
+    /// the request was HEAD, so the response has no body whatever its `content-length`
+    pub is_head: bool,
+    /// the body of an `expect: 100-continue` request, held back until the server agrees
+    pub withheld_body: Option<Bytes>,
+    /// set once the withheld body may be sent
+    send_withheld: bool,
+    #[derivative(Debug = "ignore")]
+    pub download_progress: Option<Progress>,
+    received: u64,
+    #[derivative(Debug = "ignore")]
+    pub upload_progress: Option<Progress>,
+    /// DATA waiting for the scheduler
+    outgoing: VecDeque<Bytes>,
+    /// whether to end the stream once `outgoing` has been sent
+    end_after_outgoing: bool,
+    /// bytes of `outgoing` sent so far, and queued in total
+    outgoing_sent: u64,
+    outgoing_total: u64,
+    /// waiting for `outgoing_sent` to reach the offset, see `when_written`
+    #[derivative(Debug = "ignore")]
+    written: VecDeque<(u64, oneshot::Sender<()>)>,
+    first_byte: Option<Duration>,
+    /// what the peer lets us send, negative when SETTINGS_INITIAL_WINDOW_SIZE shrank it
+    /// below what was already sent
+    window_remaining: i64,
+    /// received, but not given back with WINDOW_UPDATE yet
+    unreleased: u32,
     state: StreamState,
+    /// whether we have sent RST_STREAM, after which the peer's frames in flight are ignored
+    reset_sent: bool,
     continuing: Option<Continuing>,
     dependency: Option<StreamId>,
     exclusive_dependency: Option<bool>,
@@ -37,16 +80,36 @@ pub struct Stream {
     headers_buffer: BytesMut,
     body_buffer: BytesMut,
     response_headers: Headers,
+    /// within the span of the connection task it was opened by
+    #[cfg(feature = "tracing")]
+    #[derivative(Debug = "ignore")]
+    span: tracing::Span,
 }
 
 impl Stream {
     #[must_use]
-    pub fn new(id: NonZeroStreamId, window_remaining: u64) -> Self {
+    pub fn new(id: NonZeroStreamId, window_remaining: i64) -> Self {
         Self {
             id,
             response_tx: None,
+            data_tx: None,
+            started: None,
+            is_head: false,
+            withheld_body: None,
+            send_withheld: false,
+            download_progress: None,
+            received: 0,
+            upload_progress: None,
+            outgoing: VecDeque::new(),
+            end_after_outgoing: false,
+            outgoing_sent: 0,
+            outgoing_total: 0,
+            written: VecDeque::new(),
+            first_byte: None,
             window_remaining,
+            unreleased: 0,
             state: StreamState::Idle,
+            reset_sent: false,
             continuing: None,
             dependency: None,
             exclusive_dependency: None,
@@ -54,24 +117,236 @@ impl Stream {
             headers_buffer: BytesMut::with_capacity(16_384 * 2),
             body_buffer: BytesMut::with_capacity(16_384 * 2),
             response_headers: Headers::new(),
+            #[cfg(feature = "tracing")]
+            span: tracing::trace_span!("stream", id = id.get()),
         }
     }
 
+    /// A stand-in for a closed stream that has been pruned already, to send frames on.
+    #[must_use]
+    pub fn forgotten(id: NonZeroStreamId) -> Self {
+        let mut stream = Self::new(id, 0);
+        stream.state = StreamState::Closed;
+        stream
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.state == StreamState::Closed
+    }
+
+    #[inline]
+    pub fn reset_sent(&self) -> bool {
+        self.reset_sent
+    }
+
+    /// Queues DATA to be sent by `StreamCoordinator::schedule_data`.
+    pub fn queue_data(&mut self, data: Bytes, end_stream: bool) {
+        self.outgoing_total += data.len() as u64;
+        if !data.is_empty() {
+            self.outgoing.push_back(data);
+        }
+        self.end_after_outgoing |= end_stream;
+    }
+
+    /// Resolves `written_tx` once everything queued so far has been sent in DATA frames,
+    /// which waits for the peer's flow-control window. Dropped if the stream fails first.
+    pub fn when_written(&mut self, written_tx: oneshot::Sender<()>) {
+        if self.outgoing_sent >= self.outgoing_total && !self.end_after_outgoing {
+            written_tx.send(()).ok();
+        } else {
+            self.written.push_back((self.outgoing_total, written_tx));
+        }
+    }
+
+    #[inline]
+    pub fn has_outgoing(&self) -> bool {
+        !self.outgoing.is_empty() || self.end_after_outgoing
+    }
+
+    /// Whether the next DATA frame can be sent within the flow-control windows. An empty one
+    /// ending the stream always can, and so can anything on a closed stream, which is dropped.
+    pub fn can_send(&self, connection_window: usize) -> bool {
+        self.has_outgoing()
+            && (self.outgoing.is_empty()
+                || self.is_closed()
+                || self.window_remaining > 0 && connection_window > 0)
+    }
+
+    /// Adjusts the window by the change in SETTINGS_INITIAL_WINDOW_SIZE, returns false if
+    /// it overflows. https://httpwg.org/specs/rfc7540.html#InitialWindowSize
+    pub fn adjust_window(&mut self, delta: i64) -> bool {
+        self.window_remaining += delta;
+        self.window_remaining <= i64::from(U31_MAX.get())
+    }
+
+    /// Sends the next DATA frame from the queue, of at most `max_frame_size` and what the
+    /// flow-control windows allow.
+    pub fn write_next_frame(&mut self, state: &mut ConnectionState, max_frame_size: usize) {
+        if self.is_closed() {
+            // reset streams don't get to send the rest
+            self.outgoing.clear();
+            self.end_after_outgoing = false;
+            return;
+        }
+        let max_size = max_frame_size
+            .min(usize::try_from(self.window_remaining).unwrap_or(0))
+            .min(state.window_remaining);
+        let data = match self.outgoing.pop_front() {
+            Some(mut data) if data.len() > max_size => {
+                let rest = data.split_off(max_size);
+                self.outgoing.push_front(rest);
+                data
+            }
+            Some(data) => data,
+            None => Bytes::new(),
+        };
+        let end_stream = self.outgoing.is_empty() && self.end_after_outgoing;
+        if end_stream {
+            self.end_after_outgoing = false;
+        }
+        self.outgoing_sent += data.len() as u64;
+        self.window_remaining -= i64::try_from(data.len()).unwrap_or(i64::MAX);
+        state.window_remaining -= data.len();
+        let empty = data.is_empty();
+        FramePayload::Data { data }.send(
+            state,
+            Some(self),
+            if end_stream {
+                DataFlags::END_STREAM
+            } else {
+                DataFlags::empty()
+            },
+        );
+        while self
+            .written
+            .front()
+            .is_some_and(|(offset, _)| *offset <= self.outgoing_sent)
+        {
+            if let Some((_, written_tx)) = self.written.pop_front() {
+                written_tx.send(()).ok();
+            }
+        }
+        if let (Some(progress), false) = (&self.upload_progress, empty) {
+            let offset = state.bytes_flushed + state.write_buf.len() as u64;
+            state.upload_progress.push_back((
+                offset,
+                Arc::clone(progress),
+                self.outgoing_sent,
+                self.outgoing_total,
+            ));
+        }
+    }
+
+    /// when the stream times out if no response HEADERS have arrived by then
+    pub fn headers_deadline(&self, timeout: Duration) -> Option<Instant> {
+        if self.is_closed() || self.first_byte.is_some() {
+            return None;
+        }
+        self.started.map(|started| started + timeout)
+    }
+
+    /// When to send the withheld body anyway, if the server hasn't answered the
+    /// `expect: 100-continue` by then.
+    pub fn continue_deadline(&self, timeout: Duration) -> Option<Instant> {
+        if self.withheld_body.is_none() || self.send_withheld || !self.response_headers.is_empty() {
+            return None;
+        }
+        self.started.map(|started| started + timeout)
+    }
+
+    pub fn time_out_continue(&mut self) {
+        trace!("No 100 Continue for stream {} in time", self.id);
+        self.send_withheld = true;
+    }
+
+    /// The withheld body, once it may be sent.
+    pub fn take_withheld(&mut self) -> Option<Bytes> {
+        if std::mem::take(&mut self.send_withheld) {
+            self.withheld_body.take()
+        } else {
+            None
+        }
+    }
+
+    /// Handles a complete header block with a 1xx status, which precedes the final response.
+    /// Returns false for any other header block.
+    /// https://httpwg.org/specs/rfc7540.html#HttpSequence
+    fn interim_response(&mut self) -> bool {
+        let Some(status) = self
+            .response_headers
+            .get(":status")
+            .and_then(|values| values.first())
+        else {
+            return false;
+        };
+        if !status.starts_with('1') {
+            return false;
+        }
+        if status == "100" && self.withheld_body.is_some() {
+            self.send_withheld = true;
+        }
+        self.response_headers.clear();
+        true
+    }
+
+    /// Whether no one waits for the response anymore.
+    pub fn is_abandoned(&self) -> bool {
+        !self.is_closed()
+            && self
+                .response_tx
+                .as_ref()
+                .is_some_and(oneshot::Sender::is_closed)
+    }
+
+    pub fn cancel(&mut self, state: &mut ConnectionState) {
+        debug!("Response for stream {} dropped, cancelling", self.id);
+        self.reset(
+            state,
+            ErrorType::Cancel,
+            RequestError::Reset(ErrorType::Cancel),
+        );
+    }
+
+    pub fn time_out_headers(&mut self, state: &mut ConnectionState) {
+        warn!("No response headers for stream {} in time", self.id);
+        self.reset(state, ErrorType::Cancel, RequestError::HeadersTimeout);
+    }
+
+    /// whether we have ended the stream
+    #[inline]
+    pub fn is_local_closed(&self) -> bool {
+        matches!(
+            self.state,
+            StreamState::HalfClosedLocal | StreamState::Closed
+        )
+    }
+
+    /// whether the peer has ended the stream
+    #[inline]
+    fn is_remote_closed(&self) -> bool {
+        matches!(
+            self.state,
+            StreamState::HalfClosedRemote | StreamState::Closed
+        )
+    }
+
     /// https://httpwg.org/specs/rfc7540.html#StreamStates
     pub fn transition_state(
         &mut self,
         recv: bool,
         ty: FrameType,
         flags: Flags,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), FrameDecodeError> {
         let send = !recv;
         let original_state = self.state;
 
         if matches!(ty, FrameType::ResetStream) {
             if self.state == StreamState::Idle {
-                return Err(anyhow!("ResetStream on Idle"));
+                return Err(FrameDecodeError::InvalidState(ty));
             }
             self.state = StreamState::Closed;
+            self.reset_sent |= send;
         } else {
             let h = match flags {
                 Flags::Headers(flags) => flags.contains(HeadersFlags::END_HEADERS),
@@ -142,35 +417,85 @@ impl Stream {
         Ok(())
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            parent = &self.span,
+            skip_all,
+            fields(ty = ?FrameType::from(&payload))
+        )
+    )]
     pub fn handle_frame(
         &mut self,
         state: &mut ConnectionState,
         payload: FramePayload,
-    ) -> anyhow::Result<()> {
-        let header = state
-            .header
-            .as_ref()
-            .ok_or_else(|| anyhow!("no header for payload"))?;
+    ) -> Result<(), FrameDecodeError> {
+        let header = state.header.as_ref().expect("header for payload");
         self.transition_state(true, header.ty, header.flags)?;
         match (header.flags, payload) {
             (Flags::Data(flags), FramePayload::Data { data, .. }) => {
-                // TODO: proper flow control
-                if let Some(increment) = NonZeroU32::new(header.length as u32) {
-                    FramePayload::WindowUpdate { increment }.write_into(
-                        &mut state.write_buf,
-                        Some(self),
-                        Flags::None,
-                    );
-                    FramePayload::WindowUpdate { increment }.write_into(
-                        &mut state.write_buf,
-                        None,
-                        Flags::None,
-                    );
+                let length = header.length as u32;
+                // give the stream's window back in batches, and not at all once it has ended
+                let threshold = state.stream_receive_window() / 2;
+                self.unreleased = self.unreleased.saturating_add(length);
+                if self.unreleased >= threshold && !flags.contains(DataFlags::END_STREAM) {
+                    let unreleased = std::mem::take(&mut self.unreleased);
+                    if let Some(increment) = NonZeroU32::new(unreleased) {
+                        FramePayload::WindowUpdate { increment }.send(
+                            state,
+                            Some(self),
+                            Flags::None,
+                        );
+                    }
+                }
+                if let Some(increment) = state.receive_window.received(length) {
+                    FramePayload::WindowUpdate { increment }.send(state, None, Flags::None);
+                }
+                if state.receive_window.wants_bdp_ping() {
+                    let payload = state.send_ping();
+                    state.receive_window.bdp_ping_sent(payload);
                 }
 
-                self.body_buffer.extend(data);
-                if flags.contains(DataFlags::END_STREAM) {
-                    self.send_response();
+                self.received += data.len() as u64;
+                // https://httpwg.org/specs/rfc7540.html#malformed
+                if self.is_head
+                    || self.content_length_mismatch(flags.contains(DataFlags::END_STREAM))
+                {
+                    warn!("Unexpected amount of DATA on stream {}", self.id);
+                    self.body_buffer = BytesMut::new();
+                    self.malformed(state);
+                    return Ok(());
+                }
+                if let Some(ref progress) = self.download_progress {
+                    let total = self
+                        .response_headers
+                        .get("content-length")
+                        .and_then(|values| values.first())
+                        .and_then(|value| value.parse().ok());
+                    progress(self.received, total);
+                }
+
+                if let Some(ref data_tx) = self.data_tx {
+                    if !data.is_empty() {
+                        // if the tunnel has been dropped, the data can just be discarded
+                        data_tx.send(Ok(data)).ok();
+                    }
+                    if flags.contains(DataFlags::END_STREAM) {
+                        self.data_tx = None;
+                    }
+                } else {
+                    let max_size = state.options.max_body_size;
+                    if self.body_buffer.len() + data.len() > max_size {
+                        warn!("Body of stream {} too large: > {}", self.id, max_size);
+                        self.body_buffer = BytesMut::new();
+                        self.reset(state, ErrorType::Cancel, RequestError::BodyTooLarge);
+                        return Ok(());
+                    }
+                    self.body_buffer.extend(data);
+                    if flags.contains(DataFlags::END_STREAM) {
+                        self.send_response();
+                    }
                 }
             }
             (
@@ -183,16 +508,20 @@ impl Stream {
                     ..
                 },
             ) => {
+                if self.first_byte.is_none() {
+                    self.first_byte = self.started.map(|started| started.elapsed());
+                }
+
                 if flags.contains(HeadersFlags::PRIORITY) {
                     self.dependency = dependency;
                     self.exclusive_dependency = exclusive_dependency;
                     self.weight = weight;
                 }
 
-                self.headers_buffer.extend(fragment);
-                if flags.contains(HeadersFlags::END_HEADERS) {
-                    self.decode_headers(&mut state.header_decoder)?;
-                } else {
+                if !self.buffer_fragment(state, fragment) {
+                    return Ok(());
+                }
+                if !flags.contains(HeadersFlags::END_HEADERS) {
                     self.continuing = Some(Continuing::Headers);
                 }
 
@@ -201,11 +530,20 @@ impl Stream {
                     flags.contains(HeadersFlags::END_STREAM),
                 ) {
                     (true, true) => {
-                        self.decode_headers(&mut state.header_decoder)?;
-                        self.send_response();
+                        self.decode_headers(state)?;
+                        if self.check_status(state) {
+                            self.end_stream_checked(state);
+                        }
+                        self.data_tx = None;
                     }
                     (true, false) => {
-                        self.decode_headers(&mut state.header_decoder)?;
+                        self.decode_headers(state)?;
+                        if self.check_status(state)
+                            && !self.interim_response()
+                            && self.data_tx.is_some()
+                        {
+                            self.send_response();
+                        }
                     }
                     (false, true | false) => {}
                 }
@@ -225,28 +563,50 @@ impl Stream {
             }
             (Flags::None, FramePayload::ResetStream { error, .. }) => {
                 warn!("Reset stream: {:?}", error);
+                self.fail(if error == ErrorType::RefusedStream {
+                    RequestError::NotProcessed
+                } else {
+                    RequestError::Reset(error)
+                });
             }
             (Flags::PushPromise(flags), FramePayload::PushPromise { fragment, .. }) => {
-                self.headers_buffer.extend(fragment);
+                if !self.buffer_fragment(state, fragment) {
+                    return Ok(());
+                }
                 if flags.contains(PushPromiseFlags::END_HEADERS) {
-                    self.decode_headers(&mut state.header_decoder)?;
+                    self.decode_headers(state)?;
                 } else {
                     self.continuing = Some(Continuing::PushPromise);
                 }
             }
             (Flags::None, FramePayload::WindowUpdate { increment, .. }) => {
-                self.window_remaining += self
-                    .window_remaining
-                    .saturating_add(u64::from(increment.get()));
+                // https://httpwg.org/specs/rfc7540.html#fc-conn-window
+                let window = self.window_remaining + i64::from(increment.get());
+                if window > i64::from(U31_MAX.get()) {
+                    warn!("Window of stream {} overflowed: {}", self.id, window);
+                    self.reset(
+                        state,
+                        ErrorType::FlowControlError,
+                        RequestError::Reset(ErrorType::FlowControlError),
+                    );
+                    return Ok(());
+                }
+                self.window_remaining = window;
             }
             (Flags::Continuation(flags), FramePayload::Continuation { fragment, .. }) => {
-                self.headers_buffer.extend(fragment);
+                if !self.buffer_fragment(state, fragment) {
+                    return Ok(());
+                }
                 if flags.contains(ContinuationFlags::END_HEADERS) {
                     self.continuing = None;
 
-                    self.decode_headers(&mut state.header_decoder)?;
-                    if self.state != StreamState::Open {
-                        self.send_response();
+                    self.decode_headers(state)?;
+                    if self.check_status(state) {
+                        if self.is_remote_closed() {
+                            self.end_stream_checked(state);
+                        } else if !self.interim_response() && self.data_tx.is_some() {
+                            self.send_response();
+                        }
                     }
                 }
             }
@@ -260,34 +620,334 @@ impl Stream {
             }
             _ => unreachable!("impossible Flags/FramePayload combo"),
         }
+        // the server answered the `expect: 100-continue` with a final response instead,
+        // so the body is never sent, and our side is closed with the response complete
+        if self.is_remote_closed() && !self.is_local_closed() && self.withheld_body.take().is_some()
+        {
+            FramePayload::ResetStream {
+                error: ErrorType::Cancel,
+            }
+            .send(state, Some(self), Flags::None);
+        }
         Ok(())
     }
 
-    fn decode_headers(
-        &mut self,
-        header_decoder: &mut hpack::Decoder<'_>,
-    ) -> Result<(), DecodeError> {
-        header_decoder
+    /// returns false if the fragment would take the buffered header block over the limit,
+    /// in which case the connection is closed
+    fn buffer_fragment(&mut self, state: &mut ConnectionState, fragment: Bytes) -> bool {
+        if self.headers_buffer.len() + fragment.len() > state.options.max_header_block_size {
+            warn!("Header block of stream {} too large", self.id);
+            state.connection_error(ErrorType::EnhanceYourCalm, b"header block too large");
+            return false;
+        }
+        self.headers_buffer.extend(fragment);
+        true
+    }
+
+    /// Resets the stream if the header list is larger than our SETTINGS_MAX_HEADER_LIST_SIZE,
+    /// or has uppercase names when not lenient about them.
+    /// The whole block is still decoded to keep the HPACK context in sync. The decoder applies
+    /// the dynamic table size updates at the start of the block itself, so its table follows
+    /// the server's encoder rather than our SETTINGS_HEADER_TABLE_SIZE.
+    fn decode_headers(&mut self, state: &mut ConnectionState) -> Result<(), FrameDecodeError> {
+        let max_size = state.options.settings[SettingsParameter::MaxHeaderListSize] as usize;
+        let lenient = state.options.lenient_header_names;
+        let mut size = 0_usize;
+        let mut uppercase = false;
+        check_table_size_updates(&self.headers_buffer, state.header_table_limit())
+            .map_err(FrameDecodeError::InvalidHeader)?;
+        state
+            .header_decoder
             .decode_with_cb(&self.headers_buffer, |key, value| {
-                self.response_headers
-                    .entry(String::from_utf8_lossy(&key).to_string())
-                    .or_default()
-                    .push(String::from_utf8_lossy(&value).to_string());
+                // https://httpwg.org/specs/rfc7540.html#SETTINGS_MAX_HEADER_LIST_SIZE
+                size = size.saturating_add(key.len() + value.len() + 32);
+                if size > max_size || uppercase {
+                    return;
+                }
+                let mut name = String::from_utf8_lossy(&key).to_string();
+                // https://httpwg.org/specs/rfc7540.html#HttpHeaders
+                if name.bytes().any(|b| b.is_ascii_uppercase()) {
+                    if lenient {
+                        name.make_ascii_lowercase();
+                    } else {
+                        uppercase = true;
+                        return;
+                    }
+                }
+                append_header(
+                    &mut self.response_headers,
+                    name,
+                    String::from_utf8_lossy(&value).to_string(),
+                );
             })
-            .map_err(DecodeError::InvalidHeader)?;
+            .map_err(FrameDecodeError::InvalidHeader)?;
         self.headers_buffer.clear();
+        if size > max_size {
+            warn!(
+                "Header list of stream {} too large: {} > {}",
+                self.id, size, max_size
+            );
+            self.reset(
+                state,
+                ErrorType::EnhanceYourCalm,
+                RequestError::HeaderListTooLarge,
+            );
+        } else if uppercase {
+            warn!("Uppercase header name on stream {}", self.id);
+            self.reset(
+                state,
+                ErrorType::ProtocolError,
+                RequestError::MalformedResponse,
+            );
+        }
         Ok(())
     }
 
+    /// Sends a RST_STREAM for a stream error on our side.
+    pub fn reset(
+        &mut self,
+        state: &mut ConnectionState,
+        error: ErrorType,
+        request_error: RequestError,
+    ) {
+        self.response_headers.clear();
+        FramePayload::ResetStream { error }.send(state, Some(self), Flags::None);
+        self.fail(request_error);
+    }
+
+    /// Closes the stream, passing the error on to whoever is waiting for the response.
+    pub fn fail(&mut self, error: RequestError) {
+        self.state = StreamState::Closed;
+        self.written.clear();
+        if let Some(data_tx) = self.data_tx.take() {
+            data_tx.send(Err(error)).ok();
+        }
+        if let Some(tx) = self.response_tx.take() {
+            tx.send(Err(error)).ok();
+        }
+    }
+
+    /// Fails the stream when the connection is lost before it was closed, with
+    /// `IncompleteResponse` if the final response headers had arrived but not END_STREAM,
+    /// rather than leaving a partial body looking complete.
+    pub fn connection_lost(&mut self) {
+        // the headers of interim responses are cleared
+        if self.response_headers.contains_key(":status") {
+            warn!(
+                "Stream {} was interrupted in the middle of the response",
+                self.id
+            );
+            self.fail(RequestError::IncompleteResponse);
+        } else {
+            self.fail(RequestError::ConnectionClosed);
+        }
+    }
+
+    /// The `content-length` of the response, unless it doesn't describe the body, as for
+    /// HEAD and 304 Not Modified.
+    fn content_length(&self) -> Option<u64> {
+        let status = self.response_headers.get(":status")?.first()?;
+        if self.is_head || status == "304" {
+            return None;
+        }
+        self.response_headers
+            .get("content-length")?
+            .first()?
+            .parse()
+            .ok()
+    }
+
+    /// Whether the DATA received so far contradicts the `content-length`, which it has to
+    /// match exactly by the end of the stream.
+    fn content_length_mismatch(&self, end_stream: bool) -> bool {
+        self.content_length().is_some_and(|expected| {
+            self.received > expected || end_stream && self.received != expected
+        })
+    }
+
+    /// A stream error for a malformed response, without a RST_STREAM if the stream is
+    /// already closed, as nothing may be sent on it then.
+    fn malformed(&mut self, state: &mut ConnectionState) {
+        if self.state == StreamState::Closed {
+            self.fail(RequestError::MalformedResponse);
+        } else {
+            self.reset(
+                state,
+                ErrorType::ProtocolError,
+                RequestError::MalformedResponse,
+            );
+        }
+    }
+
+    /// Whether the response has exactly one `:status`, a three-digit code, and otherwise
+    /// treats it as malformed, unless the stream has failed already.
+    /// https://httpwg.org/specs/rfc7540.html#HttpResponse
+    fn check_status(&mut self, state: &mut ConnectionState) -> bool {
+        let valid = matches!(
+            self.response_headers.get(":status").map(Vec::as_slice),
+            Some([status]) if status.len() == 3
+                && status.starts_with(['1', '2', '3', '4', '5'])
+                && status.bytes().all(|b| b.is_ascii_digit())
+        );
+        if !valid && (self.response_tx.is_some() || self.data_tx.is_some()) {
+            warn!("Missing or invalid :status on stream {}", self.id);
+            self.malformed(state);
+        }
+        valid
+    }
+
+    /// Sends the response once the peer has ended the stream with HEADERS, unless it was
+    /// short of its `content-length`.
+    fn end_stream_checked(&mut self, state: &mut ConnectionState) {
+        if self.content_length_mismatch(true) {
+            warn!("Body of stream {} shorter than its content-length", self.id);
+            self.malformed(state);
+        } else {
+            self.send_response();
+        }
+    }
+
     fn send_response(&mut self) {
         if let Some(tx) = self.response_tx.take() {
             let response = Response {
                 headers: self.response_headers.clone(),
                 body: self.body_buffer.clone().freeze(),
+                timing: Timing {
+                    first_byte: self.first_byte,
+                    ..Timing::default()
+                },
+                tls_session: None,
+                version: Version::Http2,
             };
             trace!("{:#?}", response);
             // if the sender isn't interested in the response anymore, no need to error out hard
-            tx.send(response).ok();
+            tx.send(Ok(response)).ok();
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // the connection is gone, don't let a streamed body look like it ended normally
+        if let Some(data_tx) = self.data_tx.take() {
+            data_tx.send(Err(RequestError::ConnectionClosed)).ok();
+        }
+    }
+}
+
+/// Decodes a header block that nobody is waiting for anymore, which still updates the
+/// decoder's dynamic table.
+pub fn discard_header_block(
+    state: &mut ConnectionState,
+    block: &[u8],
+) -> Result<(), FrameDecodeError> {
+    check_table_size_updates(block, state.header_table_limit())
+        .map_err(FrameDecodeError::InvalidHeader)?;
+    state
+        .header_decoder
+        .decode_with_cb(block, |_, _| {})
+        .map_err(FrameDecodeError::InvalidHeader)
+}
+
+/// Checks the dynamic table size updates at the start of a header block against the largest
+/// size we allow, before the decoder applies them. The decoder doesn't limit them, and panics
+/// on a truncated one.
+///
+/// https://www.rfc-editor.org/rfc/rfc7541.html#section-4.2
+fn check_table_size_updates(mut block: &[u8], limit: usize) -> Result<(), DecoderError> {
+    while block.first().is_some_and(|first| first & 0xe0 == 0x20) {
+        let (size, consumed) = decode_integer(block, 5)?;
+        if size > limit {
+            return Err(DecoderError::InvalidMaxDynamicSize);
+        }
+        block = &block[consumed..];
+    }
+    Ok(())
+}
+
+/// Decodes an integer with an N-bit prefix from the start of `block`, returning it and the
+/// number of bytes it took.
+///
+/// https://www.rfc-editor.org/rfc/rfc7541.html#section-5.1
+fn decode_integer(block: &[u8], prefix: u8) -> Result<(usize, usize), DecoderError> {
+    let error = |error| Err(DecoderError::IntegerDecodingError(error));
+    let Some(&first) = block.first() else {
+        return error(IntegerDecodingError::NotEnoughOctets);
+    };
+    let max_prefix = (1_usize << prefix) - 1;
+    let mut value = usize::from(first) & max_prefix;
+    if value < max_prefix {
+        return Ok((value, 1));
+    }
+    // more than 4 continuation bytes don't fit in 32 bits
+    for (i, &byte) in block.iter().enumerate().skip(1).take(4) {
+        value += usize::from(byte & 0x7f) << (7 * (i - 1));
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
         }
     }
+    if block.len() > 5 {
+        error(IntegerDecodingError::TooManyOctets)
+    } else {
+        error(IntegerDecodingError::NotEnoughOctets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers() {
+        assert!(matches!(decode_integer(&[0x0a], 5), Ok((10, 1))));
+        // https://www.rfc-editor.org/rfc/rfc7541.html#appendix-C.1.2
+        assert!(matches!(
+            decode_integer(&[0x1f, 0x9a, 0x0a, 0xff], 5),
+            Ok((1337, 3))
+        ));
+        for truncated in [
+            &[][..],
+            &[0x1f],
+            &[0x1f, 0x9a],
+            &[0x1f, 0xff, 0xff, 0xff, 0xff],
+        ] {
+            assert!(matches!(
+                decode_integer(truncated, 5),
+                Err(DecoderError::IntegerDecodingError(
+                    IntegerDecodingError::NotEnoughOctets
+                ))
+            ));
+        }
+        assert!(matches!(
+            decode_integer(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0x7f], 5),
+            Err(DecoderError::IntegerDecodingError(
+                IntegerDecodingError::TooManyOctets
+            ))
+        ));
+    }
+
+    #[test]
+    fn table_size_updates() {
+        // a size update to 4096, then :method GET
+        let block = [0x3f, 0xe1, 0x1f, 0x82];
+        assert!(check_table_size_updates(&block, 4096).is_ok());
+        assert!(matches!(
+            check_table_size_updates(&block, 4095),
+            Err(DecoderError::InvalidMaxDynamicSize)
+        ));
+        // each of several updates is checked, not only the last
+        assert!(check_table_size_updates(&[0x20, 0x3f, 0xe1, 0x1f], 4096).is_ok());
+        assert!(matches!(
+            check_table_size_updates(&[0x3f, 0xe1, 0x1f, 0x20], 100),
+            Err(DecoderError::InvalidMaxDynamicSize)
+        ));
+        assert!(matches!(
+            check_table_size_updates(&[0x20, 0x3f, 0xe1], 4096),
+            Err(DecoderError::IntegerDecodingError(
+                IntegerDecodingError::NotEnoughOctets
+            ))
+        ));
+        // no size update at all
+        assert!(check_table_size_updates(&[0x82], 0).is_ok());
+    }
 }
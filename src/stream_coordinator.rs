@@ -1,9 +1,17 @@
-use crate::{stream::Stream, types::*};
+use crate::{connection::ConnectionState, priority::PriorityTree, stream::Stream, types::*};
+use bytes::Bytes;
 use derivative::Derivative;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     sync::atomic::{AtomicU32, Ordering},
 };
+use tokio::{
+    sync::oneshot,
+    time::{Duration, Instant},
+};
+
+/// how many of the streams we reset are remembered after they have been pruned
+const MAX_REMEMBERED_RESETS: usize = 64;
 
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -11,20 +19,256 @@ pub struct StreamCoordinator {
     client_id: AtomicU32,
     #[derivative(Debug = "ignore")]
     streams: HashMap<NonZeroStreamId, Stream>,
+    /// orders the streams with DATA queued
+    priority: PriorityTree,
+    /// streams we've opened over the lifetime of the connection
+    opened: u64,
+    /// the peer's SETTINGS_INITIAL_WINDOW_SIZE, which new streams start with
+    initial_window: i64,
+    /// the latest of the pruned streams that we reset, see `was_reset`
+    reset: VecDeque<NonZeroStreamId>,
 }
 
 impl StreamCoordinator {
     pub fn get_mut(&mut self, id: NonZeroStreamId) -> &mut Stream {
-        // TODO: initial window size
+        let initial_window = self.initial_window;
         self.streams
             .entry(id)
-            .or_insert_with(|| Stream::new(id, 65_535))
+            .or_insert_with(|| Stream::new(id, initial_window))
+    }
+
+    /// Applies a new SETTINGS_INITIAL_WINDOW_SIZE to the streams' send windows, returns false
+    /// if one of them overflows.
+    /// https://httpwg.org/specs/rfc7540.html#InitialWindowSize
+    pub fn set_initial_window(&mut self, size: u32) -> bool {
+        let delta = i64::from(size) - self.initial_window;
+        self.initial_window = i64::from(size);
+        let mut ok = true;
+        for stream in self.streams.values_mut() {
+            ok &= stream.adjust_window(delta);
+        }
+        ok
+    }
+
+    /// unlike `get_mut`, doesn't create the stream
+    pub fn existing_mut(&mut self, id: NonZeroStreamId) -> Option<&mut Stream> {
+        self.streams.get_mut(&id)
+    }
+
+    /// whether the ID belongs to a stream we've opened at some point
+    pub fn was_opened(&self, id: NonZeroStreamId) -> bool {
+        id.get() % 2 == 1 && id.get() < self.client_id.load(Ordering::SeqCst)
+    }
+
+    /// number of streams we've opened, including closed ones
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.opened
+    }
+
+    /// whether the ID belongs to one of the recently pruned streams that we reset, whose
+    /// frames may still be in flight
+    /// https://httpwg.org/specs/rfc7540.html#StreamStates
+    pub fn was_reset(&self, id: NonZeroStreamId) -> bool {
+        self.reset.contains(&id)
+    }
+
+    /// Forgets closed streams, frames still arriving for them are handled like for any
+    /// stream that `was_opened`.
+    pub fn prune_closed(&mut self) {
+        let priority = &mut self.priority;
+        let reset = &mut self.reset;
+        self.streams.retain(|&id, stream| {
+            if stream.is_closed() {
+                priority.remove(id.get());
+                if stream.reset_sent() {
+                    if reset.len() == MAX_REMEMBERED_RESETS {
+                        reset.pop_front();
+                    }
+                    reset.push_back(id);
+                }
+            }
+            !stream.is_closed()
+        });
+    }
+
+    /// number of streams that aren't closed yet
+    pub fn active(&self) -> usize {
+        self.streams
+            .values()
+            .filter(|stream| !stream.is_closed())
+            .count()
+    }
+
+    /// fails our streams above `last_stream`, which the peer won't process
+    pub fn fail_above(&mut self, last_stream: StreamId, error: RequestError) {
+        for stream in self.streams.values_mut() {
+            // only the odd, client-initiated streams are ours
+            if stream.id.get() > last_stream && stream.id.get() % 2 == 1 {
+                stream.fail(error);
+            }
+        }
+    }
+
+    /// fails the streams that haven't been closed yet, when the connection is lost
+    pub fn connection_lost(&mut self) {
+        for stream in self.streams.values_mut() {
+            if !stream.is_closed() {
+                stream.connection_lost();
+            }
+        }
+    }
+
+    /// resets the streams whose response was dropped before it arrived
+    pub fn cancel_abandoned(&mut self, state: &mut ConnectionState) {
+        for stream in self.streams.values_mut() {
+            if stream.is_abandoned() {
+                stream.cancel(state);
+            }
+        }
+    }
+
+    /// the earliest deadline of the streams still waiting for response HEADERS
+    pub fn headers_deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.streams
+            .values()
+            .filter_map(|stream| stream.headers_deadline(timeout))
+            .min()
+    }
+
+    /// resets the streams whose response HEADERS didn't arrive within `timeout`
+    pub fn time_out_headers(&mut self, state: &mut ConnectionState, timeout: Duration) {
+        let now = Instant::now();
+        for stream in self.streams.values_mut() {
+            if matches!(stream.headers_deadline(timeout), Some(deadline) if deadline <= now) {
+                stream.time_out_headers(state);
+            }
+        }
+    }
+
+    /// the earliest deadline of the streams with a body waiting for 100 Continue
+    pub fn continue_deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.streams
+            .values()
+            .filter_map(|stream| stream.continue_deadline(timeout))
+            .min()
+    }
+
+    /// sends the withheld bodies of the streams that got no 100 Continue within `timeout`
+    pub fn time_out_continue(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .streams
+            .values_mut()
+            .filter(|stream| {
+                matches!(stream.continue_deadline(timeout), Some(deadline) if deadline <= now)
+            })
+            .map(|stream| {
+                stream.time_out_continue();
+                stream.id
+            })
+            .collect();
+        for id in expired {
+            self.release_withheld(id);
+        }
+    }
+
+    /// Queues the withheld body of the stream, if the server has agreed to it.
+    pub fn release_withheld(&mut self, id: NonZeroStreamId) {
+        let body = self.streams.get_mut(&id).and_then(Stream::take_withheld);
+        if let Some(body) = body {
+            self.queue_data(id, body, true);
+        }
+    }
+
+    /// Queues DATA on the stream, to be sent once `schedule_data` gets to it.
+    pub fn queue_data(&mut self, id: NonZeroStreamId, data: Bytes, end_stream: bool) {
+        // the stream may have been closed and pruned already
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        stream.queue_data(data, end_stream);
+        if stream.has_outgoing() {
+            self.priority.insert(id.get());
+        }
+    }
+
+    /// See `Stream::when_written`, dropped if the stream is gone already.
+    pub fn when_written(&mut self, id: NonZeroStreamId, written_tx: oneshot::Sender<()>) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.when_written(written_tx);
+        }
+    }
+
+    /// Moves queued DATA into the write buffer one frame at a time, in the order of the
+    /// priority tree, so that a large upload doesn't hold up the others. Only tops the
+    /// buffer up to about a frame, the rest waits until it has been flushed.
+    pub fn schedule_data(&mut self, state: &mut ConnectionState) {
+        let max_frame_size = state.their_settings[SettingsParameter::MaxFrameSize] as usize;
+        while state.write_buf.len() < max_frame_size {
+            let streams = &self.streams;
+            let connection_window = state.window_remaining;
+            let ready = |id| {
+                NonZeroStreamId::new(id)
+                    .and_then(|id| streams.get(&id))
+                    .is_some_and(|stream| stream.can_send(connection_window))
+            };
+            let Some(id) = self.priority.pick(ready).and_then(NonZeroStreamId::new) else {
+                break;
+            };
+            let stream = self.streams.get_mut(&id).expect("picked stream");
+            let buffered = state.write_buf.len();
+            stream.write_next_frame(state, max_frame_size);
+            self.priority
+                .sent(id.get(), state.write_buf.len().saturating_sub(buffered));
+            // done sending for good, its dependents take its place
+            if !stream.has_outgoing() && stream.is_local_closed() {
+                self.priority.remove(id.get());
+            }
+        }
+    }
+
+    /// https://httpwg.org/specs/rfc7540.html#PRIORITY
+    pub fn reprioritize(
+        &mut self,
+        state: &mut ConnectionState,
+        id: NonZeroStreamId,
+        dependency: StreamId,
+        exclusive: bool,
+        weight: u8,
+    ) {
+        if dependency == id.get() {
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.reset(
+                    state,
+                    ErrorType::ProtocolError,
+                    RequestError::MalformedResponse,
+                );
+            }
+            return;
+        }
+        // closed, pruned and idle streams aren't tracked, so that the peer can't grow the
+        // tree without bounds
+        if !self.streams.contains_key(&id) {
+            return;
+        }
+        // a dependency on a stream that isn't in the tree gives the default priority
+        // https://httpwg.org/specs/rfc7540.html#pri-depend
+        let known = NonZeroStreamId::new(dependency)
+            .is_none_or(|dependency| self.streams.contains_key(&dependency));
+        if known {
+            self.priority
+                .reprioritize(id.get(), dependency, exclusive, weight);
+        } else {
+            self.priority.set_default(id.get());
+        }
     }
 
     /// returns None if the connection is out of stream IDs
     pub fn create_mut(&mut self) -> Option<&mut Stream> {
-        NonZeroStreamId::new(self.client_id.fetch_add(2, Ordering::SeqCst))
-            .map(|id| self.get_mut(id))
+        let id = NonZeroStreamId::new(self.client_id.fetch_add(2, Ordering::SeqCst))?;
+        self.opened += 1;
+        Some(self.get_mut(id))
     }
 }
 
@@ -34,6 +278,35 @@ impl Default for StreamCoordinator {
         Self {
             client_id: AtomicU32::new(3),
             streams: HashMap::new(),
+            priority: PriorityTree::default(),
+            opened: 0,
+            initial_window: 65_535,
+            reset: VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_of_unknown_streams_is_ignored() {
+        let mut streams = StreamCoordinator::default();
+        let mut state = ConnectionState::default();
+        let id = streams.create_mut().expect("stream").id;
+        let (first, second) = (id.get() + 2, id.get() + 4);
+        // a chain of idle streams, each depending on the one before
+        for dependency in (first..10_000).step_by(2) {
+            let stream = NonZeroStreamId::new(dependency + 2).unwrap();
+            streams.reprioritize(&mut state, stream, dependency, false, 15);
+        }
+        // and our stream depending on idle ones
+        for dependency in (first..10_000).step_by(2) {
+            streams.reprioritize(&mut state, id, dependency, true, 15);
         }
+        assert_eq!(streams.priority.len(), 2);
+        assert_eq!(streams.priority.pick(|_| true), Some(id.get()));
+        assert!(!streams.priority.contains(second));
     }
 }
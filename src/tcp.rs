@@ -0,0 +1,86 @@
+use std::{io, net::SocketAddr};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    task::JoinSet,
+    time::{sleep_until, Duration, Instant},
+};
+
+/// https://www.rfc-editor.org/rfc/rfc8305.html#section-5
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Happy Eyeballs: https://www.rfc-editor.org/rfc/rfc8305.html
+///
+/// Starts a connection attempt every `CONNECTION_ATTEMPT_DELAY`, or as soon as the previous
+/// one fails, alternating between address families. The first to connect wins, and the
+/// rest are cancelled. With a `local_addr` only the addresses of its family are tried.
+pub async fn connect(
+    mut addrs: Vec<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    if let Some(local_addr) = local_addr {
+        addrs.retain(|addr| addr.is_ipv4() == local_addr.is_ipv4());
+    }
+    let mut addrs = interleave_families(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut next_attempt = Instant::now();
+    let mut last_err = None;
+    loop {
+        let more = addrs.len() > 0;
+        if attempts.is_empty() && !more {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+            }));
+        }
+        tokio::select! {
+            () = sleep_until(next_attempt), if more => {
+                if let Some(addr) = addrs.next() {
+                    attempts.spawn(connect_one(addr, local_addr));
+                }
+                next_attempt = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+            }
+            Some(result) = attempts.join_next(), if !attempts.is_empty() => {
+                match result.map_err(io::Error::other)? {
+                    // dropping the JoinSet aborts the other attempts
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => {
+                        last_err = Some(err);
+                        next_attempt = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn connect_one(addr: SocketAddr, local_addr: Option<SocketAddr>) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    if let Some(local_addr) = local_addr {
+        socket.bind(local_addr)?;
+    }
+    socket.connect(addr).await
+}
+
+/// https://www.rfc-editor.org/rfc/rfc8305.html#section-4
+///
+/// Keeps the family of the first address first, and the order within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let ipv6_first = !matches!(addrs.first(), Some(addr) if addr.is_ipv4());
+    let len = addrs.len();
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == ipv6_first);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(len);
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
@@ -0,0 +1,364 @@
+use crate::types::RequestError;
+use ring::digest::{digest, SHA256};
+use std::{
+    io, iter,
+    net::SocketAddr,
+    pin::Pin as Pinned,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, Error, ServerName,
+};
+
+/// The TLS implementation connections are made with, see
+/// [`ClientBuilder::tls_backend`](crate::ClientBuilder::tls_backend).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TlsBackend {
+    /// rustls, trusting the Mozilla roots of `webpki-roots`.
+    #[default]
+    Rustls,
+    /// The platform's TLS library through `native-tls`, trusting the system's roots. There's
+    /// no 0-RTT early data, and pins can only match the end-entity certificate.
+    #[cfg(feature = "native-tls")]
+    NativeTls,
+}
+
+/// Makes the TLS connections, for the backend chosen with [`TlsBackend`].
+#[derive(Clone)]
+pub enum Connector {
+    Rustls(tokio_rustls::TlsConnector),
+    #[cfg(feature = "native-tls")]
+    NativeTls(tokio_native_tls::TlsConnector),
+}
+
+/// A TLS connection made by either backend. Past the handshake only reading and writing it,
+/// the negotiated protocol and the server's certificate are needed.
+pub enum TlsStream {
+    Rustls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+    #[cfg(feature = "native-tls")]
+    NativeTls(tokio_native_tls::TlsStream<TcpStream>),
+}
+
+impl TlsStream {
+    /// The protocol the server chose with ALPN.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Rustls(stream) => stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(stream) => stream.get_ref().negotiated_alpn().ok().flatten(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Rustls(stream) => stream.get_ref().0.peer_addr().ok(),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(stream) => stream.get_ref().get_ref().get_ref().peer_addr().ok(),
+        }
+    }
+
+    /// The server's end-entity certificate in DER.
+    pub fn peer_certificate(&self) -> Option<Arc<[u8]>> {
+        match self {
+            Self::Rustls(stream) => stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .map(|certificate| Arc::from(certificate.0.as_slice())),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(stream) => stream
+                .get_ref()
+                .peer_certificate()
+                .ok()
+                .flatten()
+                .and_then(|certificate| certificate.to_der().ok())
+                .map(Arc::from),
+        }
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pinned<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Rustls(stream) => Pinned::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(stream) => Pinned::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        self: Pinned<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Rustls(stream) => Pinned::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(stream) => Pinned::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pinned<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Rustls(stream) => Pinned::new(stream).poll_flush(cx),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(stream) => Pinned::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pinned<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Rustls(stream) => Pinned::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(stream) => Pinned::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Builds the `native-tls` connector, offering `h2` and with the fallback `http/1.1` with ALPN.
+#[cfg(feature = "native-tls")]
+pub fn native_tls_connector(
+    options: &crate::connection::ConnectionOptions,
+) -> native_tls::Result<tokio_native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if options.http1_fallback {
+        builder.request_alpns(&["h2", "http/1.1"]);
+    } else {
+        builder.request_alpns(&["h2"]);
+    }
+    builder.danger_accept_invalid_certs(options.danger_accept_invalid_certs);
+    Ok(builder.build()?.into())
+}
+
+/// Completes the handshake with `native-tls`, which verifies the certificate itself. Only
+/// the end-entity certificate is available to check the pins against.
+#[cfg(feature = "native-tls")]
+pub async fn connect_native_tls(
+    connector: &tokio_native_tls::TlsConnector,
+    server_name: &str,
+    tcp: TcpStream,
+    pins: &[Pin],
+) -> anyhow::Result<TlsStream> {
+    let stream = TlsStream::NativeTls(connector.connect(server_name, tcp).await?);
+    if !pins.is_empty() {
+        let pinned = stream
+            .peer_certificate()
+            .is_some_and(|certificate| pins.iter().any(|pin| pin.matches(&certificate)));
+        if !pinned {
+            return Err(RequestError::PinMismatch.into());
+        }
+    }
+    Ok(stream)
+}
+
+/// Accepts any certificate for any name, see
+/// [`ClientBuilder::danger_accept_invalid_certs`](crate::ClientBuilder::danger_accept_invalid_certs).
+/// The handshake signature is still checked, so the server does need the certificate's key.
+pub struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A SHA-256 hash that a certificate in the server's chain must match, see
+/// [`ClientBuilder::pin`](crate::ClientBuilder::pin).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pin {
+    /// Hash of the whole DER certificate, i.e. its fingerprint.
+    Certificate([u8; 32]),
+    /// Hash of the DER SubjectPublicKeyInfo, like HPKP's `pin-sha256`. It stays the same
+    /// when a certificate is renewed with the same key.
+    PublicKey([u8; 32]),
+}
+
+impl Pin {
+    fn matches(&self, certificate: &[u8]) -> bool {
+        let (hash, data) = match self {
+            Self::Certificate(hash) => (hash, Some(certificate)),
+            Self::PublicKey(hash) => (hash, subject_public_key_info(certificate)),
+        };
+        data.is_some_and(|data| digest(&SHA256, data).as_ref() == hash)
+    }
+}
+
+const PIN_MISMATCH: &str = "certificate pin mismatch";
+
+/// Verifies the certificate with `verifier` and then requires a certificate of the chain
+/// to match one of the pins.
+pub struct PinnedCertificates {
+    pub verifier: Arc<dyn ServerCertVerifier>,
+    pub pins: Vec<Pin>,
+}
+
+impl ServerCertVerifier for PinnedCertificates {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let pinned = iter::once(end_entity)
+            .chain(intermediates)
+            .any(|certificate| self.pins.iter().any(|pin| pin.matches(&certificate.0)));
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(Error::General(PIN_MISMATCH.to_owned()))
+        }
+    }
+}
+
+/// Turns a failed TLS handshake into [`RequestError::PinMismatch`] if that's why it failed.
+pub fn handshake_error(err: io::Error) -> anyhow::Error {
+    let pin_mismatch = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<Error>())
+        .is_some_and(|inner| matches!(inner, Error::General(message) if message == PIN_MISMATCH));
+    if pin_mismatch {
+        RequestError::PinMismatch.into()
+    } else {
+        err.into()
+    }
+}
+
+/// The DER SubjectPublicKeyInfo of a DER certificate, which is the seventh field of the
+/// TBSCertificate counting the optional version.
+///
+/// https://www.rfc-editor.org/rfc/rfc5280.html#section-4.1
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_split(certificate)?;
+    let (_, mut fields, _) = der_split(certificate)?;
+    if fields.first() == Some(&0xa0) {
+        fields = der_split(fields)?.2;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        fields = der_split(fields)?.2;
+    }
+    der_split(fields).map(|(spki, _, _)| spki)
+}
+
+/// Splits off the DER element at the start of `data`, returning the whole element, its
+/// contents and the rest of `data`.
+fn der_split(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let length = *data.get(1)?;
+    let (length, header) = if length & 0x80 == 0 {
+        (usize::from(length), 2)
+    } else {
+        let octets = usize::from(length & 0x7f);
+        if octets == 0 || octets > 4 {
+            return None;
+        }
+        let length = data
+            .get(2..2 + octets)?
+            .iter()
+            .fold(0, |length, &byte| length << 8 | usize::from(byte));
+        (length, 2 + octets)
+    };
+    let end = header.checked_add(length)?;
+    Some((data.get(..end)?, data.get(header..end)?, data.get(end..)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERTIFICATE: &[u8] = include_bytes!("../tests/data/cert.der");
+    const PUBLIC_KEY_PIN: &str = "UQGcSLTx2XVuQK0y64aDwh58BjAtYpjNLf1uTBScnro=";
+
+    fn pin(encoded: &str) -> [u8; 32] {
+        base64::decode(encoded).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn public_key_pin() {
+        let spki = subject_public_key_info(CERTIFICATE).unwrap();
+        assert_eq!(spki[0], 0x30);
+        assert!(Pin::PublicKey(pin(PUBLIC_KEY_PIN)).matches(CERTIFICATE));
+        assert!(!Pin::PublicKey([0; 32]).matches(CERTIFICATE));
+    }
+
+    #[test]
+    fn certificate_pin() {
+        let fingerprint = digest(&SHA256, CERTIFICATE).as_ref().try_into().unwrap();
+        assert!(Pin::Certificate(fingerprint).matches(CERTIFICATE));
+        assert!(!Pin::Certificate(pin(PUBLIC_KEY_PIN)).matches(CERTIFICATE));
+    }
+
+    #[test]
+    fn truncated_certificate() {
+        for length in 0..CERTIFICATE.len() {
+            let truncated = &CERTIFICATE[..length];
+            assert_eq!(subject_public_key_info(truncated), None, "{}", length);
+            assert!(!Pin::PublicKey(pin(PUBLIC_KEY_PIN)).matches(truncated));
+        }
+    }
+
+    #[test]
+    fn der_lengths() {
+        let short = [0x04, 0x02, 1, 2, 9];
+        assert_eq!(
+            der_split(&short),
+            Some((&short[..4], &[1, 2][..], &[9][..]))
+        );
+        let long = [0x04, 0x81, 0x02, 1, 2];
+        assert_eq!(der_split(&long), Some((&long[..], &[1, 2][..], &[][..])));
+        let two_octets = [0x04, 0x82, 0x00, 0x01, 1];
+        assert_eq!(
+            der_split(&two_octets),
+            Some((&two_octets[..], &[1][..], &[][..]))
+        );
+    }
+
+    #[test]
+    fn invalid_der() {
+        // Indefinite length isn't DER.
+        assert_eq!(der_split(&[0x30, 0x80, 1, 0, 0]), None);
+        assert_eq!(der_split(&[0x04, 0x85, 0, 0, 0, 0, 1, 1]), None);
+        assert_eq!(der_split(&[0x04, 0x84, 0xff, 0xff, 0xff, 0xff, 1]), None);
+        assert_eq!(der_split(&[0x04, 0x82, 0x01]), None);
+        assert_eq!(der_split(&[0x04, 0x03, 1, 2]), None);
+        assert_eq!(der_split(&[0x04]), None);
+        assert_eq!(der_split(&[]), None);
+        assert_eq!(
+            subject_public_key_info(&[0x30, 0x80, 0x30, 0x80, 0, 0, 0, 0]),
+            None
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{connection::Message, response::Response, types::*};
+use bytes::{Buf, Bytes};
+use derivative::Derivative;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, oneshot},
+};
+
+type Write = Pin<Box<dyn Future<Output = io::Result<()>> + Send + Sync>>;
+
+/// A bidirectional byte stream over the DATA frames of an established CONNECT stream.
+/// https://httpwg.org/specs/rfc7540.html#CONNECT
+///
+/// Sending waits until the data has gone out within the peer's flow-control windows, so a
+/// slow peer holds the sender back instead of the data piling up in memory. With
+/// [`AsyncWrite`], a write is accepted right away and the next one, or a flush, waits for
+/// it. Shutting down half-closes the tunnel like [`close`](Self::close). Stream errors are
+/// returned as [`io::Error`]s when reading.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Tunnel {
+    stream_id: NonZeroStreamId,
+    response: Response,
+    incoming: mpsc::UnboundedReceiver<Result<Bytes, RequestError>>,
+    outgoing: mpsc::Sender<Message>,
+    /// received, but not read yet
+    chunk: Bytes,
+    /// the `AsyncWrite` in progress
+    #[derivative(Debug = "ignore")]
+    write: Option<Write>,
+    /// set once the shutdown has been sent
+    closed: bool,
+}
+
+impl Tunnel {
+    pub(crate) fn new(
+        stream_id: NonZeroStreamId,
+        response: Response,
+        incoming: mpsc::UnboundedReceiver<Result<Bytes, RequestError>>,
+        outgoing: mpsc::Sender<Message>,
+    ) -> Self {
+        Self {
+            stream_id,
+            response,
+            incoming,
+            outgoing,
+            chunk: Bytes::new(),
+            write: None,
+            closed: false,
+        }
+    }
+
+    /// The response that established the tunnel.
+    #[inline]
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    /// Returns once the data has been sent.
+    pub async fn send(&self, data: impl Into<Bytes>) -> anyhow::Result<()> {
+        Ok(self.write(data.into(), false).await?)
+    }
+
+    /// Half-closes the tunnel; data can still be received after this.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        Ok(self.write(Bytes::new(), true).await?)
+    }
+
+    /// Returns None once the peer has closed its side of the tunnel, or the tunnel has failed.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        if self.chunk.has_remaining() {
+            return Some(std::mem::take(&mut self.chunk));
+        }
+        self.incoming.recv().await?.ok()
+    }
+
+    fn write(&self, data: Bytes, end_stream: bool) -> Write {
+        let outgoing = self.outgoing.clone();
+        let stream_id = self.stream_id;
+        Box::pin(async move {
+            let (written_tx, written_rx) = oneshot::channel();
+            outgoing
+                .send(Message::TunnelData {
+                    stream_id,
+                    data,
+                    end_stream,
+                    written_tx,
+                })
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            written_rx
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        })
+    }
+
+    /// Completes the write in progress, if any.
+    fn poll_write_done(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(ref mut write) = self.write else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = std::task::ready!(write.as_mut().poll(cx));
+        self.write = None;
+        Poll::Ready(result)
+    }
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while !self.chunk.has_remaining() {
+            match std::task::ready!(self.incoming.poll_recv(cx)) {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+                // the peer has closed its side
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = self.chunk.len().min(buf.remaining());
+        buf.put_slice(&self.chunk.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        std::task::ready!(self.poll_write_done(cx))?;
+        if self.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        self.write = Some(self.write(Bytes::copy_from_slice(buf), false));
+        // gets the data on its way, futures do nothing until polled
+        if let Poll::Ready(Err(err)) = self.poll_write_done(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_write_done(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        std::task::ready!(self.poll_write_done(cx))?;
+        if !self.closed {
+            self.closed = true;
+            self.write = Some(self.write(Bytes::new(), true));
+            std::task::ready!(self.poll_write_done(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
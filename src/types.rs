@@ -1,5 +1,10 @@
+use indexmap::IndexMap;
 use num_derive::{FromPrimitive, ToPrimitive};
-use std::{collections::HashMap, num::NonZeroU32};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    num::NonZeroU32,
+};
 
 // Safety: value is a const, that can't be zero
 pub const U31_MAX: NonZeroU32 = unsafe { NonZeroU32::new_unchecked(u32::MAX >> 1) };
@@ -7,36 +12,171 @@ pub const U31_MAX: NonZeroU32 = unsafe { NonZeroU32::new_unchecked(u32::MAX >> 1
 pub type StreamId = u32;
 pub type NonZeroStreamId = std::num::NonZeroU32;
 
-pub type Headers = HashMap<String, Vec<String>>;
+/// Header names and their values, in the order the names first appeared.
+///
+/// A header received in several fields has a value per field, in order. Values aren't
+/// joined with commas, which would break `set-cookie`. The exception is `cookie`, which
+/// HTTP/2 may split into a field per cookie: those are joined back into a single value with
+/// `; `, and sent that way over HTTP/1.1 too.
+///
+/// https://httpwg.org/specs/rfc7540.html#CompressCookie
+pub type Headers = IndexMap<String, Vec<String>>;
 
+/// Every `RandomState` is seeded differently, which is random enough for jitter and
+/// multipart boundaries, but not for anything that has to be unpredictable.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Adds a received header field, see [`Headers`] for how the values are kept.
+pub(crate) fn append_header(headers: &mut Headers, name: String, value: String) {
+    if name == "cookie" {
+        if let Some(cookie) = headers
+            .get_mut("cookie")
+            .and_then(|values| values.first_mut())
+        {
+            cookie.push_str("; ");
+            cookie.push_str(&value);
+            return;
+        }
+    }
+    headers.entry(name).or_default().push(value);
+}
+
+/// An alternative service the server advertised with an ALTSVC frame.
+///
+/// https://www.rfc-editor.org/rfc/rfc7838.html#section-4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AltSvc {
+    /// The origin it's for, or `None` when it was sent on the stream of a request and is for
+    /// the origin of that request.
+    pub origin: Option<String>,
+    /// The `Alt-Svc` field value, e.g. `h3=":443"; ma=86400`, or `clear` to drop the
+    /// alternatives advertised before.
+    pub value: String,
+}
+
+/// Why a received frame couldn't be decoded or processed. Apart from `TooShort`, which only
+/// means more bytes have to be read first, these are connection errors.
 #[derive(thiserror::Error, Debug)]
-pub enum DecodeError {
+pub enum FrameDecodeError {
     #[error("Not enough bytes to decode frame")]
     TooShort,
-    #[error("Unknown frame type")]
-    UnknownType,
+    #[error("Frame larger than SETTINGS_MAX_FRAME_SIZE: {0}")]
+    FrameTooLarge(usize),
+    #[error("The server connection preface must start with SETTINGS, got {0:?}")]
+    InvalidPreface(FrameType),
+    #[error("Invalid length for {0:?}: {1}")]
+    InvalidLength(FrameType, usize),
+    #[error("Padding longer than the frame payload")]
+    InvalidPadding,
     #[error("Unexpected 0 stream ID")]
     ZeroStreamId,
     #[error("Unexpected 0 window increment")]
     ZeroWindowIncrement,
-    #[error("Unknown error type")]
-    UnknownErrorType,
+    #[error("{0:?} not allowed in the stream's state")]
+    InvalidState(FrameType),
     #[error("Invalid header: {0:?}")]
     InvalidHeader(hpack::decoder::DecoderError),
 }
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestError {
     #[error("The connection ran out of stream IDs")]
     OutOfStreamIds,
+    /// Only `https` and `wss` URLs are supported, as connections always use TLS.
+    #[error("The URL scheme isn't supported")]
+    UnsupportedScheme,
     #[error("Request authority cannot be a base")]
     AuthorityCannotBeBase,
+    #[error("The :authority override is empty")]
+    EmptyAuthority,
+    #[error("The server doesn't support extended CONNECT")]
+    ExtendedConnectNotSupported,
+    /// The server is guaranteed not to have processed the request, so it's safe to retry.
+    #[error("The request wasn't processed by the server")]
+    NotProcessed,
+    #[error("The stream was reset: {0:?}")]
+    Reset(ErrorType),
+    #[error("The connection was closed")]
+    ConnectionClosed,
+    /// The response headers had arrived, but the connection was lost before the end of the
+    /// response.
+    #[error("The connection was closed before the response was complete")]
+    IncompleteResponse,
+    #[error("The response headers exceeded the maximum header list size")]
+    HeaderListTooLarge,
+    #[error("Connection-specific header not allowed in HTTP/2: {0}")]
+    ConnectionSpecificHeader(&'static str),
+    #[error("The te header may only contain \"trailers\"")]
+    InvalidTe,
+    /// A request header name isn't a token, or a value contains CR, LF or NUL.
+    #[error("Invalid request header")]
+    InvalidHeader,
+    #[error("The response was malformed")]
+    MalformedResponse,
+    #[error("The response body exceeded the maximum body size")]
+    BodyTooLarge,
+    #[error("No response headers were received in time")]
+    HeadersTimeout,
+    /// The request didn't complete within its `Request::timeout`.
+    #[error("The request timed out")]
+    Timeout,
+    #[error("The server's certificate chain doesn't match any of the pins")]
+    PinMismatch,
+    /// The server chose HTTP/1.1 with ALPN, see `ClientBuilder::http1_fallback`.
+    #[error("The server only supports HTTP/1.1")]
+    Http1Only,
+}
+
+impl FrameDecodeError {
+    /// The RST_STREAM code for the errors that only affect the stream the frame was on, all
+    /// others are connection errors.
+    /// https://httpwg.org/specs/rfc7540.html#StreamErrorHandler
+    pub fn stream_error(&self) -> Option<ErrorType> {
+        match self {
+            // https://httpwg.org/specs/rfc7540.html#WINDOW_UPDATE
+            Self::ZeroWindowIncrement => Some(ErrorType::ProtocolError),
+            // https://httpwg.org/specs/rfc7540.html#PRIORITY
+            Self::InvalidLength(FrameType::Priority, _) => Some(ErrorType::FrameSizeError),
+            _ => None,
+        }
+    }
+}
+
+/// The code of the GOAWAY that closes the connection on the error.
+impl From<FrameDecodeError> for ErrorType {
+    fn from(error: FrameDecodeError) -> Self {
+        match error {
+            FrameDecodeError::TooShort
+            | FrameDecodeError::FrameTooLarge(_)
+            | FrameDecodeError::InvalidLength(..) => Self::FrameSizeError,
+            FrameDecodeError::InvalidHeader(_) => Self::CompressionError,
+            FrameDecodeError::ZeroStreamId
+            | FrameDecodeError::ZeroWindowIncrement
+            | FrameDecodeError::InvalidPadding
+            | FrameDecodeError::InvalidPreface(_)
+            | FrameDecodeError::InvalidState(_) => Self::ProtocolError,
+        }
+    }
 }
 
 /// https://httpwg.org/specs/rfc7540.html#FrameTypes
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, FromPrimitive, ToPrimitive)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    FromPrimitive,
+    ToPrimitive,
+    enum_map::Enum,
+)]
 #[repr(u8)]
-//#[non_exhaustive]
+#[non_exhaustive]
 pub enum FrameType {
     Data = 0x0,
     Headers = 0x1,
@@ -48,6 +188,13 @@ pub enum FrameType {
     GoAway = 0x7,
     WindowUpdate = 0x8,
     Continuation = 0x9,
+    /// https://www.rfc-editor.org/rfc/rfc7838.html#section-4
+    AltSvc = 0xa,
+    /// https://www.rfc-editor.org/rfc/rfc8336.html
+    Origin = 0xc,
+    /// Any type we don't know, such frames are skipped. Never sent, so the code is only a
+    /// stand-in. https://httpwg.org/specs/rfc9113.html#FrameHeader
+    Unknown = 0xff,
 }
 
 /// https://httpwg.org/specs/rfc7540.html#ErrorCodes
@@ -120,4 +267,8 @@ pub enum SettingsParameter {
     /// This advisory setting informs a peer of the maximum size of header list that the sender is prepared to accept, in octets. The value is based on the uncompressed size of header fields, including the length of the name and value in octets plus an overhead of 32 octets for each header field.
     /// For any given request, a lower limit than what is advertised MAY be enforced. The initial value of this setting is unlimited.
     MaxHeaderListSize = 0x6,
+    /// https://www.rfc-editor.org/rfc/rfc8441.html#section-3
+    /// Upon receipt of SETTINGS_ENABLE_CONNECT_PROTOCOL with a value of 1, a client MAY use the Extended CONNECT as defined in this document when creating new streams. Receipt of this parameter by a server does not have any impact.
+    /// The initial value is 0. A sender MUST NOT send a SETTINGS_ENABLE_CONNECT_PROTOCOL parameter with the value of 0 after previously sending a value of 1.
+    EnableConnectProtocol = 0x8,
 }
@@ -0,0 +1,88 @@
+use log::debug;
+use std::num::NonZeroU32;
+use tokio::time::Duration;
+
+/// https://httpwg.org/specs/rfc7540.html#InitialWindowSize
+const INITIAL_WINDOW: u32 = 65_535;
+
+/// Receive-side flow control for the whole connection.
+///
+/// The window starts at the default and grows toward the bandwidth-delay product, which is
+/// sampled with a PING: the bytes received while it's in flight are about what the connection
+/// carries per round trip. Received bytes are given back with a WINDOW_UPDATE once they add
+/// up to half the window.
+/// https://httpwg.org/specs/rfc7540.html#FlowControl
+#[derive(Debug)]
+pub struct ReceiveWindow {
+    /// the window we keep open for the peer
+    size: u32,
+    /// the most `size` can grow to
+    max: u32,
+    /// received, but not given back yet
+    unreleased: u32,
+    /// payload of the PING sampling the BDP, and the bytes received since it was sent
+    bdp_ping: Option<(u64, u32)>,
+    /// the best bandwidth seen, in bytes per second
+    max_bandwidth: f64,
+}
+
+impl ReceiveWindow {
+    #[must_use]
+    pub fn new(max: u32) -> Self {
+        Self {
+            size: INITIAL_WINDOW,
+            max: max.max(INITIAL_WINDOW),
+            unreleased: 0,
+            bdp_ping: None,
+            max_bandwidth: 0.0,
+        }
+    }
+
+    /// Accounts for a DATA frame, returns the increment to give back if it's time to.
+    pub fn received(&mut self, length: u32) -> Option<NonZeroU32> {
+        self.unreleased = self.unreleased.saturating_add(length);
+        if let Some((_, ref mut bytes)) = self.bdp_ping {
+            *bytes = bytes.saturating_add(length);
+        }
+        if self.unreleased >= self.size / 2 {
+            NonZeroU32::new(std::mem::take(&mut self.unreleased))
+        } else {
+            None
+        }
+    }
+
+    /// Whether a PING should be sent to sample the BDP, which is pointless at the maximum.
+    #[inline]
+    pub fn wants_bdp_ping(&self) -> bool {
+        self.bdp_ping.is_none() && self.size < self.max
+    }
+
+    #[inline]
+    pub fn bdp_ping_sent(&mut self, payload: u64) {
+        self.bdp_ping = Some((payload, 0));
+    }
+
+    /// Returns the increment to send if the window grew.
+    pub fn ping_acked(&mut self, payload: u64, rtt: Duration) -> Option<NonZeroU32> {
+        let bdp = match self.bdp_ping {
+            Some((bdp_payload, bytes)) if bdp_payload == payload => bytes,
+            _ => return None,
+        };
+        self.bdp_ping = None;
+
+        let bandwidth = f64::from(bdp) / rtt.as_secs_f64().max(f64::EPSILON);
+        // only grow when the window, rather than a slower network, was limiting the transfer
+        if bdp < self.size / 3 * 2 || bandwidth < self.max_bandwidth {
+            return None;
+        }
+        self.max_bandwidth = bandwidth;
+        let size = bdp.saturating_mul(2).clamp(self.size, self.max);
+        debug!(
+            "Receive window {} -> {} (BDP {} over {:?})",
+            self.size, size, bdp, rtt
+        );
+        let increment = size - self.size;
+        self.size = size;
+        NonZeroU32::new(increment)
+    }
+}
@@ -24,3 +24,18 @@ async fn google_redirect() {
         .unwrap();
     assert_eq!(response.status(), 200);
 }
+
+#[tokio::test]
+async fn streaming_body() {
+    let client = Client::default();
+    let (response, mut body) = client
+        .request_streaming(Request::get("https://example.com/".try_into().unwrap()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let mut text = Vec::new();
+    tokio::io::copy(&mut body, &mut text).await.unwrap();
+    assert!(String::from_utf8(text)
+        .unwrap()
+        .contains("This domain is for use in illustrative examples in documents."));
+}
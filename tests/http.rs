@@ -0,0 +1,31 @@
+#![cfg(feature = "http")]
+use http2::{Bytes, Client, Method, Request};
+
+#[test]
+fn request_duplicate_headers() {
+    let request = http::Request::post("https://example.com/path?query")
+        .header("accept", "text/html")
+        .header("accept", "application/json")
+        .body(Bytes::from_static(b"body"))
+        .unwrap();
+    let request = Request::try_from(request).unwrap();
+    assert!(matches!(request.method, Method::Post));
+    assert_eq!(request.url.as_str(), "https://example.com/path?query");
+    assert_eq!(
+        request.headers["accept"],
+        vec!["text/html".to_owned(), "application/json".to_owned()]
+    );
+    assert_eq!(request.body, Bytes::from_static(b"body"));
+}
+
+#[tokio::test]
+async fn example_com() {
+    let client = Client::default();
+    let request = http::Request::get("https://example.com/")
+        .body(Bytes::new())
+        .unwrap();
+    let response = client.request(request.try_into().unwrap()).await.unwrap();
+    let response = http::Response::try_from(response).unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+    assert_eq!(response.version(), http::Version::HTTP_2);
+}
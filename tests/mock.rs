@@ -0,0 +1,1380 @@
+//! Tests against a fake server on the other end of an in-memory stream.
+
+use http2::{Client, Direction, ErrorType, FrameType, Request, RequestError, Url};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+fn frame(ty: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = u32::try_from(payload.len()).unwrap().to_be_bytes()[1..].to_vec();
+    frame.extend([ty, flags]);
+    frame.extend(stream_id.to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+/// The type, flags, stream ID and payload of the next frame from the client.
+async fn read_frame(server: &mut (impl AsyncRead + Unpin)) -> (u8, u8, u32, Vec<u8>) {
+    let mut header = [0; 9];
+    server.read_exact(&mut header).await.unwrap();
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let stream_id = u32::from_be_bytes(header[5..].try_into().unwrap()) & (u32::MAX >> 1);
+    let mut payload = vec![0; length];
+    server.read_exact(&mut payload).await.unwrap();
+    (header[3], header[4], stream_id, payload)
+}
+
+/// Connects `client` to a fake server that sends SETTINGS with `params` as its preface,
+/// and reads the client's preface.
+async fn connect(client: &Client, params: &[(u16, u32)]) -> (Url, DuplexStream) {
+    let (io, mut server) = duplex(1 << 16);
+    let url = Url::parse("https://example.com/").unwrap();
+    let settings: Vec<u8> = params
+        .iter()
+        .flat_map(|(param, value)| [&param.to_be_bytes()[..], &value.to_be_bytes()].concat())
+        .collect();
+    server
+        .write_all(&frame(SETTINGS, 0, 0, &settings))
+        .await
+        .unwrap();
+    client.connect_io(&url, io).await.unwrap();
+    let mut preface = [0; PREFACE.len()];
+    server.read_exact(&mut preface).await.unwrap();
+    assert_eq!(preface, PREFACE);
+    (url, server)
+}
+
+/// The flags, stream ID and payload of the next frame of type `ty`, skipping the others.
+async fn next_frame(server: &mut (impl AsyncRead + Unpin), ty: u8) -> (u8, u32, Vec<u8>) {
+    loop {
+        let (frame_ty, flags, stream_id, payload) = read_frame(server).await;
+        if frame_ty == ty {
+            return (flags, stream_id, payload);
+        }
+    }
+}
+
+fn status(status: &str) -> Vec<u8> {
+    hpack::Encoder::new().encode([(&b":status"[..], status.as_bytes())])
+}
+
+#[tokio::test]
+async fn response() {
+    let (io, mut server) = duplex(1 << 16);
+    let url = Url::parse("https://example.com/").unwrap();
+    let client = Client::default();
+    server.write_all(&frame(SETTINGS, 0, 0, &[])).await.unwrap();
+    client.connect_io(&url, io).await.unwrap();
+
+    let server = tokio::spawn(async move {
+        let mut preface = [0; PREFACE.len()];
+        server.read_exact(&mut preface).await.unwrap();
+        assert_eq!(preface, PREFACE);
+        // skip the SETTINGS and such until the request
+        let stream_id = loop {
+            let (ty, flags, stream_id, _) = read_frame(&mut server).await;
+            if ty == HEADERS {
+                assert_eq!(flags & END_STREAM, END_STREAM);
+                break stream_id;
+            }
+        };
+        let block = hpack::Encoder::new().encode([(&b":status"[..], &b"200"[..])]);
+        let response = [
+            frame(HEADERS, END_HEADERS, stream_id, &block),
+            frame(DATA, END_STREAM, stream_id, b"hello"),
+        ]
+        .concat();
+        server.write_all(&response).await.unwrap();
+        server
+    });
+
+    let response = client.request(Request::get(url)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text(), "hello");
+    drop(server.await.unwrap());
+}
+
+#[tokio::test]
+async fn timeout() {
+    let (io, mut server) = duplex(1 << 16);
+    let url = Url::parse("https://example.com/").unwrap();
+    let client = Client::default();
+    server.write_all(&frame(SETTINGS, 0, 0, &[])).await.unwrap();
+    client.connect_io(&url, io).await.unwrap();
+
+    // the server reads the request, but never answers
+    let server = tokio::spawn(async move {
+        let mut buf = vec![0; 1 << 16];
+        while server.read(&mut buf).await.is_ok_and(|read| read > 0) {}
+    });
+
+    let err = client
+        .request(Request::get(url).timeout(Duration::from_millis(100)))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::Timeout)
+    ));
+    server.abort();
+}
+
+#[tokio::test]
+async fn cancel_on_drop() {
+    let (io, mut server) = duplex(1 << 16);
+    let url = Url::parse("https://example.com/").unwrap();
+    let client = Client::default();
+    server.write_all(&frame(SETTINGS, 0, 0, &[])).await.unwrap();
+    client.connect_io(&url, io).await.unwrap();
+
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let mut preface = [0; PREFACE.len()];
+    server.read_exact(&mut preface).await.unwrap();
+    let stream_id = loop {
+        let (ty, _, stream_id, _) = read_frame(&mut server).await;
+        if ty == HEADERS {
+            break stream_id;
+        }
+    };
+    request.abort();
+
+    let (_, _, reset_id, error) = loop {
+        let frame = read_frame(&mut server).await;
+        if frame.0 == RST_STREAM {
+            break frame;
+        }
+    };
+    assert_eq!(reset_id, stream_id);
+    // CANCEL
+    assert_eq!(error, 0x8u32.to_be_bytes());
+
+    // what the server sent before it saw the reset is ignored, rather than reset again
+    server
+        .write_all(
+            &[
+                frame(DATA, 0, stream_id, b"late"),
+                frame(PING, 0, 0, b"12345678"),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+    let (ty, flags, _, payload) = read_frame(&mut server).await;
+    assert_eq!((ty, flags, &payload[..]), (PING, ACK, &b"12345678"[..]));
+}
+
+#[tokio::test]
+async fn tunnel_flow_control() {
+    let client = Client::default();
+    // SETTINGS_INITIAL_WINDOW_SIZE
+    let (url, mut server) = connect(&client, &[(0x4, 4)]).await;
+    let tunnel = tokio::spawn({
+        let client = client.clone();
+        async move {
+            client
+                .tunnel(Request::connect(url, "example.org:443"))
+                .await
+        }
+    });
+    let (flags, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    assert_eq!(flags & END_STREAM, 0);
+    server
+        .write_all(&frame(HEADERS, END_HEADERS, stream_id, &status("200")))
+        .await
+        .unwrap();
+    let mut tunnel = tunnel.await.unwrap().unwrap();
+
+    tunnel.write_all(b"hello world").await.unwrap();
+    let (_, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!(data, b"hell");
+    // the rest waits for the window
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), tunnel.flush())
+            .await
+            .is_err()
+    );
+    server
+        .write_all(&frame(WINDOW_UPDATE, 0, stream_id, &100_u32.to_be_bytes()))
+        .await
+        .unwrap();
+    tunnel.flush().await.unwrap();
+    let (_, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!(data, b"o world");
+    tunnel.shutdown().await.unwrap();
+    let (flags, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!((flags, data.len()), (END_STREAM, 0));
+
+    server
+        .write_all(&frame(DATA, END_STREAM, stream_id, b"pong"))
+        .await
+        .unwrap();
+    let mut received = String::new();
+    tunnel.read_to_string(&mut received).await.unwrap();
+    assert_eq!(received, "pong");
+}
+
+#[tokio::test]
+async fn refused_stream_is_retried() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, refused_id, _) = next_frame(&mut server, HEADERS).await;
+    // REFUSED_STREAM
+    server
+        .write_all(&frame(RST_STREAM, 0, refused_id, &0x7_u32.to_be_bytes()))
+        .await
+        .unwrap();
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    assert!(stream_id > refused_id);
+    server
+        .write_all(
+            &[
+                frame(HEADERS, END_HEADERS, stream_id, &status("200")),
+                frame(DATA, END_STREAM, stream_id, b"hello"),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.text(), "hello");
+}
+
+#[tokio::test]
+async fn frame_on_closed_stream() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+
+    server
+        .write_all(&frame(DATA, 0, stream_id, b"late"))
+        .await
+        .unwrap();
+    let (_, reset_id, error) = next_frame(&mut server, RST_STREAM).await;
+    assert_eq!(reset_id, stream_id);
+    // STREAM_CLOSED
+    assert_eq!(error, 0x5_u32.to_be_bytes());
+}
+
+#[tokio::test]
+async fn te_is_matched_case_insensitively() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = Request::get(url.clone()).header("TE", "Trailers");
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(request).await }
+    });
+    let (_, stream_id, block) = next_frame(&mut server, HEADERS).await;
+    let headers = hpack::Decoder::new().decode(&block).unwrap();
+    assert!(headers.contains(&(b"te".to_vec(), b"trailers".to_vec())));
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+
+    let request = Request::get(url).header("te", "gzip");
+    let err = client.request(request).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::InvalidTe)
+    ));
+}
+
+#[tokio::test]
+async fn upload_waits_for_the_send_window() {
+    let client = Client::default();
+    // SETTINGS_INITIAL_WINDOW_SIZE
+    let (url, mut server) = connect(&client, &[(0x4, 10)]).await;
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let request = Request::post(url, "0123456789abcdefghijklmno").on_upload_progress({
+        let progress = Arc::clone(&progress);
+        move |sent, total| progress.lock().unwrap().push((sent, total))
+    });
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(request).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    let (flags, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!((flags, &data[..]), (0, &b"0123456789"[..]));
+    // nothing more until the window opens
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), read_frame(&mut server))
+            .await
+            .is_err()
+    );
+    assert_eq!(*progress.lock().unwrap(), [(10, Some(25))]);
+
+    server
+        .write_all(&frame(WINDOW_UPDATE, 0, stream_id, &100_u32.to_be_bytes()))
+        .await
+        .unwrap();
+    let (flags, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!((flags, &data[..]), (END_STREAM, &b"abcdefghijklmno"[..]));
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+    assert_eq!(*progress.lock().unwrap(), [(10, Some(25)), (25, Some(25))]);
+}
+
+#[tokio::test]
+async fn window_updates_are_batched() {
+    let client = Client::default();
+    // the server never acknowledges the client's SETTINGS, so it holds the stream to the
+    // default window of 65,535
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    server
+        .write_all(&frame(HEADERS, END_HEADERS, stream_id, &status("200")))
+        .await
+        .unwrap();
+    let chunk = vec![b'a'; 16_000];
+    for _ in 0..4 {
+        server
+            .write_all(&frame(DATA, 0, stream_id, &chunk))
+            .await
+            .unwrap();
+    }
+    // the stream's window is given back before it runs out
+    loop {
+        let (_, window_id, _) = next_frame(&mut server, WINDOW_UPDATE).await;
+        if window_id == stream_id {
+            break;
+        }
+    }
+    server
+        .write_all(&frame(DATA, END_STREAM, stream_id, &chunk))
+        .await
+        .unwrap();
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.text().len(), 5 * chunk.len());
+
+    let stats = client.stats();
+    let data_frames = stats.frames_received[FrameType::Data];
+    assert_eq!(data_frames, 5);
+    assert!(
+        stats.frames_sent[FrameType::WindowUpdate] < data_frames,
+        "{} WINDOW_UPDATEs for {} DATA frames",
+        stats.frames_sent[FrameType::WindowUpdate],
+        data_frames
+    );
+}
+
+#[tokio::test]
+async fn uploads_take_turns() {
+    let client = Client::default();
+    // SETTINGS_INITIAL_WINDOW_SIZE, so that both uploads wait until they're queued
+    let (url, mut server) = connect(&client, &[(0x4, 0)]).await;
+    let requests: Vec<_> = [b'a', b'b']
+        .into_iter()
+        .map(|byte| {
+            let client = client.clone();
+            let request = Request::post(url.clone(), vec![byte; 3 * 16_384]);
+            tokio::spawn(async move { client.request(request).await })
+        })
+        .collect();
+    let (_, first, _) = next_frame(&mut server, HEADERS).await;
+    let (_, second, _) = next_frame(&mut server, HEADERS).await;
+
+    let window = [&0x4_u16.to_be_bytes()[..], &(1_u32 << 16).to_be_bytes()].concat();
+    server
+        .write_all(
+            &[
+                frame(WINDOW_UPDATE, 0, 0, &(1_u32 << 20).to_be_bytes()),
+                frame(SETTINGS, 0, 0, &window),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+    let mut order = Vec::new();
+    while order.len() < 6 {
+        let (_, stream_id, data) = next_frame(&mut server, DATA).await;
+        assert_eq!(data.len(), 16_384);
+        order.push(stream_id);
+    }
+    assert!(order.windows(2).all(|pair| pair[0] != pair[1]), "{order:?}");
+    assert_eq!(order.iter().filter(|&&id| id == first).count(), 3);
+    assert_eq!(order.iter().filter(|&&id| id == second).count(), 3);
+
+    for stream_id in [first, second] {
+        server
+            .write_all(&frame(
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                stream_id,
+                &status("204"),
+            ))
+            .await
+            .unwrap();
+    }
+    for request in requests {
+        assert_eq!(request.await.unwrap().unwrap().status(), 204);
+    }
+}
+
+#[tokio::test]
+async fn unknown_frame_types_are_skipped() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    server
+        .write_all(
+            &[
+                frame(0xfa, 0xff, 0, b"extension"),
+                frame(HEADERS, END_HEADERS, stream_id, &status("200")),
+                frame(0xfb, 0, stream_id, &[0; 100]),
+                frame(DATA, END_STREAM, stream_id, b"hello"),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().text(), "hello");
+}
+
+#[tokio::test]
+async fn unknown_error_codes_are_internal_errors() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        let url = url.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    server
+        .write_all(&frame(RST_STREAM, 0, stream_id, &0xdead_u32.to_be_bytes()))
+        .await
+        .unwrap();
+    let err = request.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::Reset(ErrorType::InternalError))
+    ));
+
+    // a GOAWAY with an unknown code still lets the streams it covers finish
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    let goaway = [stream_id.to_be_bytes(), 0xbeef_u32.to_be_bytes()].concat();
+    server
+        .write_all(
+            &[
+                frame(GOAWAY, 0, 0, &goaway),
+                frame(HEADERS, END_HEADERS | END_STREAM, stream_id, &status("204")),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+}
+
+#[tokio::test]
+async fn stream_errors_reset_the_stream() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        let url = url.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    // PRIORITY is 5 octets
+    server
+        .write_all(&frame(PRIORITY, 0, stream_id, &[0; 4]))
+        .await
+        .unwrap();
+    let (_, reset_id, error) = next_frame(&mut server, RST_STREAM).await;
+    assert_eq!(reset_id, stream_id);
+    // FRAME_SIZE_ERROR
+    assert_eq!(error, 0x6_u32.to_be_bytes());
+    let err = request.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::Reset(ErrorType::FrameSizeError))
+    ));
+
+    // the connection is still up
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+}
+
+#[tokio::test]
+async fn connection_errors_send_goaway() {
+    let client = Client::default();
+    let (_, mut server) = connect(&client, &[]).await;
+    // PING is 8 octets
+    server.write_all(&frame(PING, 0, 0, &[0; 7])).await.unwrap();
+    let (_, _, payload) = next_frame(&mut server, GOAWAY).await;
+    // FRAME_SIZE_ERROR
+    assert_eq!(payload[4..8], 0x6_u32.to_be_bytes());
+}
+
+#[tokio::test]
+async fn window_overflow() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::post(url, "body")).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    // takes the stream's window of 65,535 past 2^31-1
+    server
+        .write_all(&frame(
+            WINDOW_UPDATE,
+            0,
+            stream_id,
+            &0x7fff_ffff_u32.to_be_bytes(),
+        ))
+        .await
+        .unwrap();
+    let (_, reset_id, error) = next_frame(&mut server, RST_STREAM).await;
+    assert_eq!(reset_id, stream_id);
+    // FLOW_CONTROL_ERROR
+    assert_eq!(error, 0x3_u32.to_be_bytes());
+    let err = request.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::Reset(ErrorType::FlowControlError))
+    ));
+
+    // on the connection's window it's a connection error
+    server
+        .write_all(&frame(WINDOW_UPDATE, 0, 0, &0x7fff_ffff_u32.to_be_bytes()))
+        .await
+        .unwrap();
+    let (_, _, payload) = next_frame(&mut server, GOAWAY).await;
+    assert_eq!(payload[4..8], 0x3_u32.to_be_bytes());
+}
+
+#[tokio::test]
+async fn zero_window_increment() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    server
+        .write_all(&frame(WINDOW_UPDATE, 0, stream_id, &0_u32.to_be_bytes()))
+        .await
+        .unwrap();
+    let (_, reset_id, error) = next_frame(&mut server, RST_STREAM).await;
+    assert_eq!(reset_id, stream_id);
+    // PROTOCOL_ERROR, like for an overflow the stream fails with the code it was reset with
+    assert_eq!(error, 0x1_u32.to_be_bytes());
+    let err = request.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::Reset(ErrorType::ProtocolError))
+    ));
+
+    // on the connection it's a connection error
+    server
+        .write_all(&frame(WINDOW_UPDATE, 0, 0, &0_u32.to_be_bytes()))
+        .await
+        .unwrap();
+    let (_, _, payload) = next_frame(&mut server, GOAWAY).await;
+    assert_eq!(payload[4..8], 0x1_u32.to_be_bytes());
+}
+
+#[tokio::test]
+async fn preface_must_start_with_settings() {
+    let (io, mut server) = duplex(1 << 16);
+    let url = Url::parse("https://example.com/").unwrap();
+    let client = Client::default();
+    server.write_all(&frame(PING, 0, 0, &[0; 8])).await.unwrap();
+    client.connect_io(&url, io).await.unwrap();
+    let mut preface = [0; PREFACE.len()];
+    server.read_exact(&mut preface).await.unwrap();
+
+    // the PING isn't answered, the connection is closed right away
+    let payload = loop {
+        let (ty, _, _, payload) = read_frame(&mut server).await;
+        assert_ne!(ty, PING);
+        if ty == GOAWAY {
+            break payload;
+        }
+    };
+    // PROTOCOL_ERROR
+    assert_eq!(payload[4..8], 0x1_u32.to_be_bytes());
+}
+
+#[tokio::test]
+async fn origin_frames() {
+    const ORIGIN: u8 = 0xc;
+    fn entries(origins: &[&str]) -> Vec<u8> {
+        origins
+            .iter()
+            .flat_map(|origin| {
+                let length = u16::try_from(origin.len()).unwrap().to_be_bytes();
+                [&length[..], origin.as_bytes()].concat()
+            })
+            .collect()
+    }
+
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let truncated = [
+        entries(&["https://c.example.com"]),
+        vec![0, 50],
+        b"https".to_vec(),
+    ]
+    .concat();
+    server
+        .write_all(
+            &[
+                // an empty entry is skipped
+                frame(
+                    ORIGIN,
+                    0,
+                    0,
+                    &entries(&["https://a.example.com", "", "https://b.example.com"]),
+                ),
+                // so is the rest of the frame after a truncated one
+                frame(ORIGIN, 0, 0, &truncated),
+                // only stream 0 counts
+                frame(ORIGIN, 0, 1, &entries(&["https://d.example.com"])),
+                frame(PING, 0, 0, &[0; 8]),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+    // the frames before the PING have been handled once it's acknowledged
+    let (flags, _, _) = next_frame(&mut server, PING).await;
+    assert_eq!(flags, ACK);
+    assert_eq!(
+        client.origins(&url),
+        [
+            "https://a.example.com",
+            "https://b.example.com",
+            "https://c.example.com"
+        ]
+    );
+}
+
+/// A TLS server with a certificate for a.test, b.test and c.test, answering every request
+/// with 204. Returns its port and the number of connections it has accepted.
+async fn tls_server() -> (u16, Arc<Mutex<usize>>) {
+    use tokio_rustls::{
+        rustls::{Certificate, PrivateKey, ServerConfig},
+        TlsAcceptor,
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(include_bytes!("data/cert.der").to_vec())],
+            PrivateKey(include_bytes!("data/key.der").to_vec()),
+        )
+        .unwrap();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    // on all addresses, so that 127.0.0.2 reaches it too
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(Mutex::new(0));
+    tokio::spawn({
+        let accepted = Arc::clone(&accepted);
+        async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                *accepted.lock().unwrap() += 1;
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut tls = acceptor.accept(tcp).await.unwrap();
+                    tls.write_all(&frame(SETTINGS, 0, 0, &[])).await.unwrap();
+                    let mut preface = [0; PREFACE.len()];
+                    tls.read_exact(&mut preface).await.unwrap();
+                    loop {
+                        let (_, stream_id, _) = next_frame(&mut tls, HEADERS).await;
+                        let response =
+                            frame(HEADERS, END_HEADERS | END_STREAM, stream_id, &status("204"));
+                        tls.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        }
+    });
+    (port, accepted)
+}
+
+#[tokio::test]
+async fn connections_are_coalesced() {
+    let (port, accepted) = tls_server().await;
+    let client = Client::builder()
+        .coalesce_connections(true)
+        .danger_accept_invalid_certs(true)
+        .resolver(move |host, port| {
+            let ip = if host == "c.test" {
+                [127, 0, 0, 2]
+            } else {
+                [127, 0, 0, 1]
+            };
+            vec![(ip, port).into()]
+        })
+        .build();
+    let get = |host: &str| {
+        let url = Url::parse(&format!("https://{}:{}/", host, port)).unwrap();
+        client.request(Request::get(url))
+    };
+
+    assert_eq!(get("a.test").await.unwrap().status(), 204);
+    // same address, and the certificate covers it
+    assert_eq!(get("b.test").await.unwrap().status(), 204);
+    assert_eq!(*accepted.lock().unwrap(), 1);
+    // the certificate covers it, but it's at another address
+    assert_eq!(get("c.test").await.unwrap().status(), 204);
+    assert_eq!(*accepted.lock().unwrap(), 2);
+    // same address, but the certificate doesn't cover it
+    assert_eq!(get("d.test").await.unwrap().status(), 204);
+    assert_eq!(*accepted.lock().unwrap(), 3);
+}
+
+#[tokio::test]
+async fn head_responses_have_no_body() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let block = hpack::Encoder::new()
+        .encode([(&b":status"[..], &b"200"[..]), (b"content-length", b"1000")]);
+    let request = tokio::spawn({
+        let client = client.clone();
+        let url = url.clone();
+        async move { client.request(Request::head(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    // content-length is the size of the body a GET would have gotten
+    server
+        .write_all(&frame(HEADERS, END_HEADERS | END_STREAM, stream_id, &block))
+        .await
+        .unwrap();
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.body.is_empty());
+
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::head(url)).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    // a HEAD response with DATA is malformed
+    server
+        .write_all(
+            &[
+                frame(HEADERS, END_HEADERS, stream_id, &block),
+                frame(DATA, 0, stream_id, b"body"),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+    let (_, reset_id, error) = next_frame(&mut server, RST_STREAM).await;
+    assert_eq!(reset_id, stream_id);
+    // PROTOCOL_ERROR
+    assert_eq!(error, 0x1_u32.to_be_bytes());
+    let err = request.await.unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::MalformedResponse)
+    ));
+}
+
+#[tokio::test]
+async fn expect_continue() {
+    let client = Client::builder()
+        .expect_continue_timeout(Duration::from_secs(10))
+        .build();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        let url = url.clone();
+        async move {
+            let request = Request::post(url, "body").header("expect", "100-continue");
+            client.request(request).await
+        }
+    });
+    let (flags, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    assert_eq!(flags & END_STREAM, 0);
+    // the body waits for the server
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), read_frame(&mut server))
+            .await
+            .is_err()
+    );
+    server
+        .write_all(&frame(HEADERS, END_HEADERS, stream_id, &status("100")))
+        .await
+        .unwrap();
+    let (flags, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!((flags, &data[..]), (END_STREAM, &b"body"[..]));
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+
+    // a final status instead means the body is never sent
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move {
+            let request = Request::post(url, "body").header("expect", "100-continue");
+            client.request(request).await
+        }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("413"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 413);
+    let (reset_id, error) = loop {
+        let (ty, _, frame_stream_id, payload) = read_frame(&mut server).await;
+        assert_ne!(ty, DATA);
+        if ty == RST_STREAM {
+            break (frame_stream_id, payload);
+        }
+    };
+    assert_eq!(reset_id, stream_id);
+    // CANCEL
+    assert_eq!(error, 0x8_u32.to_be_bytes());
+}
+
+#[tokio::test]
+async fn invalid_table_size_updates() {
+    // a size update to 1 MiB, more than the 4,096 octets allowed, and a truncated one
+    for block in [&[0x3f, 0xff, 0xff, 0x3f, 0x88][..], &[0x3f, 0xff]] {
+        let client = Client::default();
+        let (url, mut server) = connect(&client, &[]).await;
+        let request = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(Request::get(url)).await }
+        });
+        let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+        server
+            .write_all(&frame(HEADERS, END_HEADERS | END_STREAM, stream_id, block))
+            .await
+            .unwrap();
+        let (_, _, payload) = next_frame(&mut server, GOAWAY).await;
+        // COMPRESSION_ERROR
+        assert_eq!(payload[4..8], 0x9_u32.to_be_bytes());
+        let err = request.await.unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RequestError>(),
+            Some(RequestError::ConnectionClosed)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn header_injection() {
+    let client = Client::default();
+    let (url, _server) = connect(&client, &[]).await;
+    let request = Request::get(url).set_header("x-test", "evil\r\nx-injected: 1");
+    let err = client.request(request).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<RequestError>(),
+        Some(&RequestError::InvalidHeader)
+    );
+}
+
+/// Answers the next request on the connection with an empty 200.
+async fn respond(server: &mut DuplexStream) {
+    let (_, stream_id, _) = next_frame(server, HEADERS).await;
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("200"),
+        ))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn idle_connections_are_closed() {
+    let client = Client::builder()
+        .idle_timeout(Duration::from_millis(100))
+        .build();
+    let (url, mut server) = connect(&client, &[]).await;
+    let (response, ()) = tokio::join!(client.request(Request::get(url)), respond(&mut server));
+    assert_eq!(response.unwrap().status(), 200);
+    assert_eq!(client.connection_count(), 1);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(client.connection_count(), 0);
+}
+
+#[tokio::test]
+async fn frame_observer() {
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let observed = Arc::clone(&frames);
+    let client = Client::builder()
+        .on_frame(move |frame| observed.lock().unwrap().push(*frame))
+        .build();
+    let (url, mut server) = connect(&client, &[]).await;
+    let (response, ()) = tokio::join!(client.request(Request::get(url)), respond(&mut server));
+    assert_eq!(response.unwrap().status(), 200);
+    let frames = frames.lock().unwrap();
+    // the server connection preface
+    let received = frames
+        .iter()
+        .find(|frame| frame.direction == Direction::Received)
+        .unwrap();
+    assert_eq!(received.ty, FrameType::Settings);
+    assert!(frames
+        .iter()
+        .any(|frame| frame.direction == Direction::Sent && frame.ty == FrameType::Headers));
+    assert!(frames
+        .iter()
+        .any(|frame| frame.direction == Direction::Received && frame.ty == FrameType::Headers));
+}
+
+#[tokio::test]
+async fn settings_resize_open_stream_windows() {
+    let client = Client::default();
+    // SETTINGS_INITIAL_WINDOW_SIZE
+    let (url, mut server) = connect(&client, &[(0x4, 5)]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::post(url, "0123456789")).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    let (_, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!(data, b"01234");
+    // the increase applies to the open stream too
+    // https://httpwg.org/specs/rfc7540.html#InitialWindowSize
+    server
+        .write_all(&frame(SETTINGS, 0, 0, &[0, 0x4, 0, 0, 0, 10]))
+        .await
+        .unwrap();
+    let (flags, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!((flags, &data[..]), (END_STREAM, &b"56789"[..]));
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+}
+
+#[tokio::test]
+async fn goaway_retries_unprocessed_streams() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        let url = url.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    next_frame(&mut server, HEADERS).await;
+
+    // a second connection to take the request once the first goes away
+    let (io, mut other) = duplex(1 << 16);
+    other.write_all(&frame(SETTINGS, 0, 0, &[])).await.unwrap();
+    client.connect_io(&url, io).await.unwrap();
+    let mut preface = [0; PREFACE.len()];
+    other.read_exact(&mut preface).await.unwrap();
+    // no stream was processed, NO_ERROR
+    let goaway = [0_u32.to_be_bytes(), 0x0_u32.to_be_bytes()].concat();
+    server
+        .write_all(&frame(GOAWAY, 0, 0, &goaway))
+        .await
+        .unwrap();
+    respond(&mut other).await;
+    assert_eq!(request.await.unwrap().unwrap().status(), 200);
+    assert_eq!(client.connection_count(), 1);
+}
+
+#[tokio::test]
+async fn timeout_while_connecting() {
+    let (port, accepted) = tls_server().await;
+    // accepts connections, but never answers the TLS handshake
+    let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stalled_addr = stalled.local_addr().unwrap();
+    let resolved = Arc::new(Mutex::new(0));
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .resolver(move |_, port| {
+            let mut resolved = resolved.lock().unwrap();
+            *resolved += 1;
+            if *resolved == 1 {
+                vec![stalled_addr]
+            } else {
+                vec![([127, 0, 0, 1], port).into()]
+            }
+        })
+        .build();
+    let url = Url::parse(&format!("https://a.test:{}/", port)).unwrap();
+
+    let err = client
+        .request(Request::get(url.clone()).timeout(Duration::from_millis(100)))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<RequestError>(),
+        Some(RequestError::Timeout)
+    ));
+    assert_eq!(client.connection_count(), 0);
+    // the next request connects afresh instead of waiting on the abandoned attempt
+    let response = client.request(Request::get(url)).await.unwrap();
+    assert_eq!(response.status(), 204);
+    assert_eq!(*accepted.lock().unwrap(), 1);
+    assert_eq!(client.connection_count(), 1);
+}
+
+#[tokio::test]
+async fn priority_chains_of_idle_streams() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    // each idle stream depending on the one before, deeper than the stack would allow
+    let chain: Vec<u8> = (1001..100_001_u32)
+        .step_by(2)
+        .flat_map(|id| {
+            frame(
+                PRIORITY,
+                0,
+                id + 2,
+                &[&id.to_be_bytes()[..], &[15]].concat(),
+            )
+        })
+        .collect();
+    server.write_all(&chain).await.unwrap();
+    server.write_all(&frame(PING, 0, 0, &[0; 8])).await.unwrap();
+    next_frame(&mut server, PING).await;
+
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::post(url, "body")).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    let (flags, _, data) = next_frame(&mut server, DATA).await;
+    assert_eq!((flags, &data[..]), (END_STREAM, &b"body"[..]));
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+}
+
+#[tokio::test]
+async fn invalid_status_is_malformed() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let blocks = [
+        hpack::Encoder::new().encode([(&b"content-type"[..], &b"text/plain"[..])]),
+        status("abc"),
+        status("2000"),
+    ];
+    for block in blocks {
+        let request = tokio::spawn({
+            let client = client.clone();
+            let url = url.clone();
+            async move { client.request(Request::get(url)).await }
+        });
+        let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+        server
+            .write_all(&frame(HEADERS, END_HEADERS, stream_id, &block))
+            .await
+            .unwrap();
+        let (_, reset_id, payload) = next_frame(&mut server, RST_STREAM).await;
+        assert_eq!(reset_id, stream_id);
+        // PROTOCOL_ERROR
+        assert_eq!(payload, 0x1_u32.to_be_bytes());
+        let err = request.await.unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RequestError>(),
+            Some(&RequestError::MalformedResponse)
+        );
+    }
+}
+
+#[tokio::test]
+async fn encoder_indexing_resumes() {
+    let client = Client::default();
+    // SETTINGS_HEADER_TABLE_SIZE
+    let (url, mut server) = connect(&client, &[(0x1, 0)]).await;
+    let mut decoder = hpack::Decoder::new();
+    let mut blocks = Vec::new();
+    for settings in [None, Some(4096_u32), None] {
+        if let Some(size) = settings {
+            let payload = [&0x1_u16.to_be_bytes()[..], &size.to_be_bytes()].concat();
+            server
+                .write_all(&frame(SETTINGS, 0, 0, &payload))
+                .await
+                .unwrap();
+            next_frame(&mut server, SETTINGS).await;
+        }
+        let request = tokio::spawn({
+            let client = client.clone();
+            let url = url.clone();
+            // a name that isn't in the static table, which the encoder indexes
+            let request = Request::get(url).set_header("x-trace", "abc");
+            async move { client.request(request).await }
+        });
+        let (_, stream_id, block) = next_frame(&mut server, HEADERS).await;
+        let headers = decoder.decode(&block).unwrap();
+        assert!(headers.contains(&(b"x-trace".to_vec(), b"abc".to_vec())));
+        server
+            .write_all(&frame(
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                stream_id,
+                &status("204"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(request.await.unwrap().unwrap().status(), 204);
+        blocks.push(block);
+    }
+    // without indexing from the start, the peer's table is dropped
+    assert_eq!(blocks[0][0], 0x20);
+    // then cleared and sized back up to 4096
+    assert_eq!(blocks[1][..4], [0x20, 0x3f, 0xe1, 0x1f]);
+    // and the repeated header is indexed
+    assert!(blocks[2].len() < blocks[1].len() - 4);
+    assert!(!blocks[2].windows(7).any(|name| name == b"x-trace"));
+}
+
+#[tokio::test]
+async fn timeout_covers_redirects() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let request = tokio::spawn({
+        let client = client.clone();
+        let request = Request::get(url).timeout(Duration::from_millis(200));
+        async move { client.request_following_redirects(request, 5).await }
+    });
+    let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+    let block = hpack::Encoder::new()
+        .encode([(&b":status"[..], &b"302"[..]), (b"location", b"/elsewhere")]);
+    server
+        .write_all(&frame(HEADERS, END_HEADERS | END_STREAM, stream_id, &block))
+        .await
+        .unwrap();
+    // the redirected request is never answered
+    next_frame(&mut server, HEADERS).await;
+    let err = request.await.unwrap().unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<RequestError>(),
+        Some(&RequestError::Timeout)
+    );
+}
+
+#[tokio::test]
+async fn table_size_update_before_indexed_fields() {
+    let client = Client::default();
+    let (url, mut server) = connect(&client, &[]).await;
+    let blocks: [&[u8]; 3] = [
+        // :status 200, then foo: bar added to the dynamic table as index 62
+        b"\x88\x40\x03foo\x03bar",
+        // a size update to 64, which foo: bar still fits in, :status 200 and foo: bar by
+        // index, then baz: qux, which evicts it
+        b"\x3f\x21\x88\xbe\x40\x03baz\x03qux",
+        // index 62 is baz: qux now
+        b"\x88\xbe",
+    ];
+    let mut responses = Vec::new();
+    for block in blocks {
+        let request = tokio::spawn({
+            let client = client.clone();
+            let url = url.clone();
+            async move { client.request(Request::get(url)).await }
+        });
+        let (_, stream_id, _) = next_frame(&mut server, HEADERS).await;
+        server
+            .write_all(&frame(HEADERS, END_HEADERS | END_STREAM, stream_id, block))
+            .await
+            .unwrap();
+        responses.push(request.await.unwrap().unwrap());
+    }
+    assert_eq!(responses[0].header("foo"), Some("bar"));
+    assert_eq!(responses[1].header("foo"), Some("bar"));
+    assert_eq!(responses[1].header("baz"), Some("qux"));
+    assert_eq!(responses[2].header("foo"), None);
+    assert_eq!(responses[2].header("baz"), Some("qux"));
+    assert!(responses.iter().all(|response| response.status() == 200));
+}
+
+#[tokio::test]
+async fn idn_authority() {
+    let (io, mut server) = duplex(1 << 16);
+    let url = Url::parse("https://münchen.de/").unwrap();
+    let client = Client::default();
+    server.write_all(&frame(SETTINGS, 0, 0, &[])).await.unwrap();
+    client.connect_io(&url, io).await.unwrap();
+    let mut preface = [0; PREFACE.len()];
+    server.read_exact(&mut preface).await.unwrap();
+
+    let request = tokio::spawn({
+        let client = client.clone();
+        async move { client.request(Request::get(url)).await }
+    });
+    let (_, stream_id, block) = next_frame(&mut server, HEADERS).await;
+    let headers = hpack::Decoder::new().decode(&block).unwrap();
+    assert!(headers.contains(&(b":authority".to_vec(), b"xn--mnchen-3ya.de".to_vec())));
+    server
+        .write_all(&frame(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            stream_id,
+            &status("204"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap().unwrap().status(), 204);
+}
+
+#[tokio::test]
+async fn concurrent_requests_share_connection() {
+    let (port, accepted) = tls_server().await;
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .resolver(|_, port| vec![([127, 0, 0, 1], port).into()])
+        .build();
+    let url = Url::parse(&format!("https://a.test:{}/", port)).unwrap();
+    let handles: Vec<_> = (0..50)
+        .map(|_| {
+            let client = client.clone();
+            let url = url.clone();
+            tokio::spawn(async move { client.request(Request::get(url)).await })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.await.unwrap().unwrap().status(), 204);
+    }
+    // the first to get there connects, the rest wait for it
+    assert_eq!(*accepted.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn unsupported_scheme() {
+    let (port, _) = tls_server().await;
+    let resolved = Arc::new(Mutex::new(Vec::new()));
+    let client = Client::builder()
+        .coalesce_connections(true)
+        .danger_accept_invalid_certs(true)
+        .resolver({
+            let resolved = Arc::clone(&resolved);
+            move |host, port| {
+                resolved.lock().unwrap().push(host.to_owned());
+                vec![([127, 0, 0, 1], port).into()]
+            }
+        })
+        .build();
+    let url =
+        |scheme: &str, host: &str| Url::parse(&format!("{}://{}:{}/", scheme, host, port)).unwrap();
+    // a connection whose certificate also covers b.test, which would be resolved to see
+    // whether the connection can be coalesced
+    let response = client.request(Request::get(url("https", "a.test")));
+    assert_eq!(response.await.unwrap().status(), 204);
+
+    let request = || Request::get(url("http", "b.test"));
+    let errors = [
+        client.request(request()).await.unwrap_err(),
+        client.request_streaming(request()).await.unwrap_err(),
+        client.tunnel(request()).await.unwrap_err(),
+    ];
+    for err in errors {
+        assert_eq!(
+            err.downcast_ref::<RequestError>(),
+            Some(&RequestError::UnsupportedScheme)
+        );
+    }
+    assert_eq!(*resolved.lock().unwrap(), ["a.test"]);
+}
@@ -0,0 +1,76 @@
+use http2::{Client, Request};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+#[tokio::test]
+async fn connect_with_basic_auth() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = format!("http://atte:p%40ss@{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut buf = [0; 1024];
+            let read = socket.read(&mut buf).await.unwrap();
+            assert_ne!(read, 0);
+            request.extend_from_slice(&buf[..read]);
+        }
+        socket
+            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    });
+
+    let client = Client::builder().proxy(proxy.parse().unwrap()).build();
+    let err = client
+        .request(Request::get("https://example.com/".try_into().unwrap()))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("407"), "{}", err);
+
+    let request = server.await.unwrap();
+    assert_eq!(
+        request,
+        "CONNECT example.com:443 HTTP/1.1\r\n\
+         host: example.com:443\r\n\
+         proxy-authorization: Basic YXR0ZTpwQHNz\r\n\r\n"
+    );
+}
+
+#[tokio::test]
+async fn connect_with_idn_host() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut buf = [0; 1024];
+            let read = socket.read(&mut buf).await.unwrap();
+            assert_ne!(read, 0);
+            request.extend_from_slice(&buf[..read]);
+        }
+        socket
+            .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    });
+
+    let client = Client::builder().proxy(proxy.parse().unwrap()).build();
+    let err = client
+        .request(Request::get("https://münchen.de/".try_into().unwrap()))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("403"), "{}", err);
+
+    let request = server.await.unwrap();
+    assert_eq!(
+        request,
+        "CONNECT xn--mnchen-3ya.de:443 HTTP/1.1\r\n\
+         host: xn--mnchen-3ya.de:443\r\n\r\n"
+    );
+}
@@ -0,0 +1,164 @@
+use http2::{Bytes, Method, Multipart, Request, RequestError};
+
+#[test]
+fn query() {
+    let request = Request::get("https://example.com/search?page=2".try_into().unwrap())
+        .query(&[("q", "a b&c"), ("lang", "fi")]);
+    assert_eq!(
+        request.url.as_str(),
+        "https://example.com/search?page=2&q=a+b%26c&lang=fi"
+    );
+}
+
+#[cfg(feature = "form")]
+#[test]
+fn post_form() {
+    let request = Request::post_form(
+        "https://example.com/login".try_into().unwrap(),
+        &[("user", "atte"), ("password", "p@ss word")],
+    )
+    .unwrap();
+    assert!(matches!(request.method, Method::Post));
+    assert_eq!(
+        request.headers["content-type"],
+        vec!["application/x-www-form-urlencoded".to_owned()]
+    );
+    assert_eq!(
+        request.body,
+        Bytes::from_static(b"user=atte&password=p%40ss+word")
+    );
+}
+
+#[test]
+fn post_multipart() {
+    let multipart = Multipart::new().text("title", "Hello").file(
+        "upload",
+        "hello.txt",
+        "text/plain",
+        Bytes::from_static(b"hello world"),
+    );
+    let request = Request::post_multipart("https://example.com/".try_into().unwrap(), &multipart);
+    let content_type = &request.headers["content-type"][0];
+    let boundary = content_type
+        .strip_prefix("multipart/form-data; boundary=")
+        .unwrap();
+    assert_eq!(
+        request.body,
+        format!(
+            "--{b}\r\n\
+             content-disposition: form-data; name=\"title\"\r\n\r\n\
+             Hello\r\n\
+             --{b}\r\n\
+             content-disposition: form-data; name=\"upload\"; filename=\"hello.txt\"\r\n\
+             content-type: text/plain\r\n\r\n\
+             hello world\r\n\
+             --{b}--\r\n",
+            b = boundary
+        )
+    );
+}
+
+#[test]
+fn header_mutators() {
+    let mut request = Request::get("https://example.com/".try_into().unwrap())
+        .header("Accept", "text/html")
+        .header("accept", "application/json");
+    assert_eq!(
+        request.headers["accept"],
+        vec!["text/html".to_owned(), "application/json".to_owned()]
+    );
+    request
+        .headers
+        .insert("X-Token".to_owned(), vec!["a".to_owned()]);
+    let request = request.set_header("x-token", "b");
+    assert_eq!(request.headers.len(), 2);
+    assert_eq!(request.headers["x-token"], vec!["b".to_owned()]);
+}
+
+#[test]
+fn header_mutators_in_place() {
+    let mut request = Request::get("https://example.com/".try_into().unwrap());
+    request
+        .append_header("Accept", "text/html")
+        .append_header("accept", "application/json");
+    assert_eq!(request.headers["accept"], ["text/html", "application/json"]);
+    request
+        .headers
+        .insert("X-Token".to_owned(), vec!["a".to_owned()]);
+    request.insert_header("x-token", "b");
+    assert_eq!(request.headers.len(), 2);
+    assert_eq!(request.headers["x-token"], ["b"]);
+}
+
+#[test]
+fn raw_body_constructors() {
+    let url: http2::Url = "https://example.com/items/1".try_into().unwrap();
+    let request = Request::put(url.clone(), "raw");
+    assert!(matches!(request.method, Method::Put));
+    assert!(request.headers.is_empty());
+    assert_eq!(request.body, Bytes::from_static(b"raw"));
+    assert!(matches!(
+        Request::post(url.clone(), vec![1, 2]).method,
+        Method::Post
+    ));
+    assert!(matches!(
+        Request::patch(url, Bytes::new()).method,
+        Method::Patch
+    ));
+}
+
+#[test]
+fn authority_default_ports() {
+    let authority = |url: &str| {
+        Request::get(url.try_into().unwrap())
+            .effective_authority()
+            .unwrap()
+    };
+    assert_eq!(authority("https://example.com/"), "example.com");
+    assert_eq!(authority("https://example.com:443/"), "example.com");
+    assert_eq!(authority("http://example.com:80/"), "example.com");
+    assert_eq!(authority("https://example.com:8443/"), "example.com:8443");
+    assert_eq!(authority("http://example.com:443/"), "example.com:443");
+}
+
+#[test]
+fn options_asterisk() {
+    let request = Request::options_asterisk("https://example.com/ignored".try_into().unwrap());
+    assert!(matches!(request.method, Method::Options));
+    assert_eq!(request.path.as_deref(), Some("*"));
+    assert_eq!(request.effective_authority().unwrap(), "example.com");
+}
+
+#[test]
+fn override_authority() {
+    let url: http2::Url = "https://127.0.0.1:8443/".try_into().unwrap();
+    let request = Request::get(url.clone()).override_authority("example.com");
+    assert_eq!(request.effective_authority().unwrap(), "example.com");
+    assert_eq!(request.url, url);
+    assert_eq!(
+        Request::get(url)
+            .override_authority("")
+            .effective_authority(),
+        Err(RequestError::EmptyAuthority)
+    );
+}
+
+#[test]
+fn auth_helpers() {
+    let request = Request::get("https://example.com/".try_into().unwrap());
+    let request = request.basic_auth("atte", Some("p@ss"));
+    assert_eq!(
+        request.headers["authorization"],
+        vec!["Basic YXR0ZTpwQHNz".to_owned()]
+    );
+    let request = request.basic_auth("atte", None::<&str>);
+    assert_eq!(
+        request.headers["authorization"],
+        vec!["Basic YXR0ZTo=".to_owned()]
+    );
+    let request = request.bearer_auth("token");
+    assert_eq!(
+        request.headers["authorization"],
+        vec!["Bearer token".to_owned()]
+    );
+}
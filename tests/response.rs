@@ -0,0 +1,109 @@
+use http2::{Bytes, Cookie, Response, SameSite, Timing, Version};
+use indexmap::indexmap;
+
+fn response(content_type: &str, body: &'static [u8]) -> Response {
+    Response {
+        headers: indexmap! {
+            ":status".to_owned() => vec!["200".to_owned()],
+            "content-type".to_owned() => vec![content_type.to_owned()],
+        },
+        body: Bytes::from_static(body),
+        timing: Timing::default(),
+        tls_session: None,
+        version: Version::Http2,
+    }
+}
+
+#[test]
+fn charset() {
+    assert_eq!(
+        response("text/html; Charset=\"ISO-8859-1\"", b"").charset(),
+        Some("ISO-8859-1")
+    );
+    assert_eq!(response("text/html", b"").charset(), None);
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn text_with_charset() {
+    assert_eq!(
+        response("text/plain; charset=iso-8859-1", b"p\xe4iv\xe4\xe4").text_with_charset(),
+        "päivää"
+    );
+    assert_eq!(
+        response("text/plain", "päivää".as_bytes()).text_with_charset(),
+        "päivää"
+    );
+}
+
+#[test]
+fn cookies() {
+    let mut response = response("text/plain", b"");
+    response.headers.insert(
+        "set-cookie".to_owned(),
+        vec![
+            "session=\"abc 123\"; Path=/; domain=.Example.com; Max-Age=3600; SECURE; HttpOnly; SameSite=lax".to_owned(),
+            "theme=dark; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=relative".to_owned(),
+            "invalid".to_owned(),
+        ],
+    );
+    assert_eq!(
+        response.cookies(),
+        vec![
+            Cookie {
+                name: "session".to_owned(),
+                value: "abc 123".to_owned(),
+                path: Some("/".to_owned()),
+                domain: Some("example.com".to_owned()),
+                max_age: Some(3600),
+                expires: None,
+                secure: true,
+                http_only: true,
+                same_site: Some(SameSite::Lax),
+            },
+            Cookie {
+                name: "theme".to_owned(),
+                value: "dark".to_owned(),
+                path: None,
+                domain: None,
+                max_age: None,
+                expires: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_owned()),
+                secure: false,
+                http_only: false,
+                same_site: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn iter() {
+    let mut response = response("text/plain", b"");
+    response.headers.insert(
+        "vary".to_owned(),
+        vec!["accept".to_owned(), "cookie".to_owned()],
+    );
+    let mut fields: Vec<_> = response.iter().collect();
+    fields.sort_unstable();
+    assert_eq!(
+        fields,
+        vec![
+            (":status", "200"),
+            ("content-type", "text/plain"),
+            ("vary", "accept"),
+            ("vary", "cookie"),
+        ]
+    );
+    let mut names: Vec<_> = response.header_names().collect();
+    names.sort_unstable();
+    assert_eq!(names, vec![":status", "content-type", "vary"]);
+}
+
+#[test]
+fn version_and_reason() {
+    let mut response = response("text/plain", b"");
+    assert_eq!(response.version(), Version::Http2);
+    assert_eq!(response.reason(), Some("OK"));
+    response.headers[":status"] = vec!["599".to_owned()];
+    assert_eq!(response.reason(), None);
+}
@@ -0,0 +1,19 @@
+use http2::RetryPolicy;
+use std::time::Duration;
+
+#[test]
+fn backoff() {
+    let policy = RetryPolicy {
+        max_retries: 5,
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_secs(1),
+        max_duration: Some(Duration::from_secs(2)),
+    };
+    let first = policy.backoff(0, Duration::ZERO).unwrap();
+    assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+    let third = policy.backoff(2, Duration::ZERO).unwrap();
+    assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+    let capped = policy.backoff(40, Duration::ZERO).unwrap();
+    assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_secs(1));
+    assert_eq!(policy.backoff(0, Duration::from_secs(2)), None);
+}
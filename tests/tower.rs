@@ -0,0 +1,13 @@
+#![cfg(feature = "tower")]
+use http2::{Client, Request};
+use tower_service::Service;
+
+#[tokio::test]
+async fn example_com() {
+    let mut client = Client::default();
+    let response = client
+        .call(Request::get("https://example.com/".try_into().unwrap()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}